@@ -0,0 +1,165 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::str::FromStr;
+
+/// A tape cell. Instruction operands are `i64` and get reduced to the cell
+/// type by `add`, `mul_add` and `from_i64`; these return `None` when the
+/// result does not fit a non-wrapping cell. Parsing one from a string
+/// rejects values that do not fit, whether it wraps or not.
+pub trait Cell: Copy + Default + Eq + Debug + FromStr + Serialize + DeserializeOwned {
+    /// The `CellWidth` naming this type
+    const WIDTH: CellWidth;
+
+    fn add(self, delta: i64) -> Option<Self>;
+    /// `self + count * factor`
+    fn mul_add(self, count: Self, factor: i64) -> Option<Self>;
+    fn from_i64(value: i64) -> Option<Self>;
+    fn from_byte(byte: u8) -> Self;
+    /// The low byte, as written by `.`
+    fn to_byte(self) -> u8;
+    /// The character with this code point, if there is one
+    fn to_char(self) -> Option<char>;
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+
+    /// Index of the first zero cell of `cells`.
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|cell| cell.is_zero())
+    }
+
+    /// Index of the last zero cell of `cells`.
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|cell| cell.is_zero())
+    }
+}
+
+macro_rules! wrapping_cell {
+    ($t:ty, $width:ident) => {
+        impl Cell for $t {
+            const WIDTH: CellWidth = CellWidth::$width;
+
+            fn add(self, delta: i64) -> Option<Self> {
+                Some(self.wrapping_add(delta as $t))
+            }
+            fn mul_add(self, count: Self, factor: i64) -> Option<Self> {
+                Some(self.wrapping_add(count.wrapping_mul(factor as $t)))
+            }
+            fn from_i64(value: i64) -> Option<Self> {
+                Some(value as $t)
+            }
+            fn from_byte(byte: u8) -> Self {
+                byte.into()
+            }
+            fn to_byte(self) -> u8 {
+                self as u8
+            }
+            fn to_char(self) -> Option<char> {
+                char::from_u32(self.try_into().ok()?)
+            }
+        }
+    };
+}
+
+wrapping_cell!(u16, U16);
+wrapping_cell!(u32, U32);
+wrapping_cell!(u64, U64);
+
+impl Cell for u8 {
+    const WIDTH: CellWidth = CellWidth::U8;
+
+    fn add(self, delta: i64) -> Option<Self> {
+        Some(self.wrapping_add(delta as u8))
+    }
+    fn mul_add(self, count: Self, factor: i64) -> Option<Self> {
+        Some(self.wrapping_add(count.wrapping_mul(factor as u8)))
+    }
+    fn from_i64(value: i64) -> Option<Self> {
+        Some(value as u8)
+    }
+    fn from_byte(byte: u8) -> Self {
+        byte
+    }
+    fn to_byte(self) -> u8 {
+        self
+    }
+    fn to_char(self) -> Option<char> {
+        Some(self.into())
+    }
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        memchr::memchr(0, cells)
+    }
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        memchr::memrchr(0, cells)
+    }
+}
+
+/// Signed cells that report overflow instead of wrapping.
+impl Cell for i64 {
+    const WIDTH: CellWidth = CellWidth::I64;
+
+    fn add(self, delta: i64) -> Option<Self> {
+        self.checked_add(delta)
+    }
+    fn mul_add(self, count: Self, factor: i64) -> Option<Self> {
+        self.checked_add(count.checked_mul(factor)?)
+    }
+    fn from_i64(value: i64) -> Option<Self> {
+        Some(value)
+    }
+    fn from_byte(byte: u8) -> Self {
+        byte.into()
+    }
+    fn to_byte(self) -> u8 {
+        self as u8
+    }
+    fn to_char(self) -> Option<char> {
+        char::from_u32(self.try_into().ok()?)
+    }
+}
+
+/// Cell types selectable at run time, see `Config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+    U64,
+    /// Signed 64-bit cells; overflowing them is an error. Optimized clear
+    /// and multiplication loops assume the loop would have terminated, so
+    /// e.g. `[-]` on a negative cell clears it rather than overflowing.
+    I64,
+}
+
+/// Evaluates `$body` with `$C` naming the cell type of the `CellWidth`
+/// `$width`, e.g. `with_cell_width!(width, C => Machine::<C>::new(..))`.
+#[macro_export]
+macro_rules! with_cell_width {
+    ($width:expr, $C:ident => $body:expr) => {
+        match $width {
+            $crate::CellWidth::U8 => {
+                type $C = u8;
+                $body
+            }
+            $crate::CellWidth::U16 => {
+                type $C = u16;
+                $body
+            }
+            $crate::CellWidth::U32 => {
+                type $C = u32;
+                $body
+            }
+            $crate::CellWidth::U64 => {
+                type $C = u64;
+                $body
+            }
+            $crate::CellWidth::I64 => {
+                type $C = i64;
+                $body
+            }
+        }
+    };
+}
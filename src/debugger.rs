@@ -0,0 +1,345 @@
+//! A line-oriented debugger, driving a `Machine` one command at a time.
+
+use crate::diagnostics::{self, snippet, Mark};
+use crate::{BfError, Cell, Instr, Machine, Status};
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+Commands:
+  step [N], s [N]             run N instructions (default 1)
+  next, n                     run one instruction, or a whole loop when at a `[`
+  continue, c                 run until a breakpoint or the end of the program
+  reverse-step, rs [N]        go back N instructions (default 1)
+  reverse-continue, rc [POS]  go back to a breakpoint, or to just before the
+                              last change of the cell at POS
+  break L:C, b L:C            stop before the instruction at line L, column C
+  tape [R], t [R]             show the cells up to R (default 8) from the pointer
+  pc, where, w                show the next instruction in the source
+  set [POS] VALUE             set the cell at POS (default the pointer) to VALUE
+  input TEXT, i TEXT          give TEXT and a newline to the program as input
+  eof                         end the input of the program
+  help, h                     show this
+  quit, q                     leave the debugger
+";
+
+/// Most cells on each side of the pointer that `tape` shows.
+const MAX_RADIUS: usize = crate::MAX_DEBUG_WINDOW;
+
+/// Debugs a program, see `HELP` for the commands.
+#[derive(Debug)]
+pub struct Debugger<C = u8> {
+    machine: Machine<C>,
+    name: String,
+    source: String,
+}
+
+impl<C: Cell> Debugger<C> {
+    /// Debugs `machine`, which runs the program parsed from `source`, the
+    /// contents of the file `name`. It can go back as far as where it is
+    /// now.
+    pub fn new(mut machine: Machine<C>, name: &str, source: &str) -> Self {
+        machine.enable_history();
+        Self {
+            machine,
+            name: name.to_string(),
+            source: source.to_string(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<C> {
+        &self.machine
+    }
+
+    /// Runs the command `line`. Returns what to show, including any output
+    /// of the program, or `None` to quit.
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let reply = match words[..] {
+            [] => String::new(),
+            ["step" | "s"] => self.run(Machine::step),
+            ["step" | "s", n] => match n.parse() {
+                Ok(n) => self.run(|machine| machine.run_for(n)),
+                Err(_) => format!("not a number of steps: `{n}`\n"),
+            },
+            ["next" | "n"] => self.next(),
+            ["continue" | "c"] => self.run(Machine::run_until_input),
+            ["reverse-step" | "rs"] => self.run(Machine::step_back),
+            ["reverse-step" | "rs", n] => match n.parse() {
+                Ok(n) => self.run(|machine| machine.back_for(n)),
+                Err(_) => format!("not a number of steps: `{n}`\n"),
+            },
+            ["reverse-continue" | "rc"] => self.run(|machine| machine.back_until(None)),
+            ["reverse-continue" | "rc", position] => match position.parse() {
+                Ok(position) => self.run(|machine| machine.back_until(Some(position))),
+                Err(_) => format!("not a cell position: `{position}`\n"),
+            },
+            ["break" | "b", pos] => self.add_breakpoint(pos),
+            ["tape" | "t"] => self.tape(8),
+            ["tape" | "t", radius] => match radius.parse() {
+                Ok(radius) if radius <= MAX_RADIUS => self.tape(radius),
+                Ok(_) => format!("at most {MAX_RADIUS} cells on each side\n"),
+                Err(_) => format!("not a number of cells: `{radius}`\n"),
+            },
+            ["pc" | "where" | "w"] => self.location(),
+            ["set", value] => self.set(&self.machine.pointer().to_string(), value),
+            ["set", position, value] => self.set(position, value),
+            ["input" | "i", ..] => {
+                let text = line.trim_start().split_once(char::is_whitespace);
+                self.machine
+                    .feed(text.map_or("", |(_, text)| text).as_bytes());
+                self.machine.feed(b"\n");
+                String::new()
+            }
+            ["eof"] => {
+                self.machine.close_input();
+                String::new()
+            }
+            ["help" | "h"] => HELP.to_string(),
+            ["quit" | "q"] => return None,
+            _ => format!("unknown command `{}`, try `help`\n", line.trim()),
+        };
+        Some(reply)
+    }
+
+    /// Runs the machine with `run` and reports where it stopped.
+    fn run(&mut self, run: impl FnOnce(&mut Machine<C>) -> Result<Status, BfError>) -> String {
+        let result = run(&mut self.machine);
+        let mut reply = self.machine.take_debug_output();
+        reply.push_str(&String::from_utf8_lossy(&self.machine.take_output()));
+        if !reply.is_empty() && !reply.ends_with('\n') {
+            reply.push('\n');
+        }
+        match result {
+            Ok(Status::Halted) => {
+                let steps = self.machine.steps();
+                writeln!(reply, "program halted after {steps} steps").unwrap();
+            }
+            Ok(Status::NeedsInput) => {
+                reply.push_str("program needs input, give it with `input` or `eof`\n");
+                reply.push_str(&self.location());
+            }
+            Ok(Status::Breakpoint) => {
+                reply.push_str("breakpoint\n");
+                reply.push_str(&self.location());
+            }
+            Ok(Status::Start) => {
+                reply.push_str("back at the start\n");
+                reply.push_str(&self.location());
+            }
+            Ok(Status::Watchpoint) => {
+                reply.push_str("the next instruction last changed the cell\n");
+                reply.push_str(&self.location());
+            }
+            Ok(Status::OutOfFuel) => reply.push_str(&self.location()),
+            Err(e) => reply.push_str(&diagnostics::render(&e, &self.name, &self.source)),
+        }
+        reply
+    }
+
+    /// Steps, running a loop to its end when about to enter it.
+    fn next(&mut self) -> String {
+        let pc = self.machine.pc();
+        let Some(&Instr::LoopBegin(end)) = self.machine.program().instrs().get(pc) else {
+            return self.run(Machine::step);
+        };
+        let after = end + 1;
+        let existed = self.machine.remove_breakpoint(after);
+        self.machine.add_breakpoint(after);
+        self.run(|machine| {
+            let status = machine.run_until_input();
+            if !existed {
+                machine.remove_breakpoint(after);
+                if machine.pc() == after {
+                    // Not a breakpoint of the user
+                    return status.map(|_| Status::OutOfFuel);
+                }
+            }
+            status
+        })
+    }
+
+    fn add_breakpoint(&mut self, pos: &str) -> String {
+        let offset = pos.split_once(':').and_then(|(line, column)| {
+            offset_of(&self.source, line.parse().ok()?, column.parse().ok()?)
+        });
+        let Some(offset) = offset else {
+            return format!("not a LINE:COLUMN in the source: `{pos}`\n");
+        };
+        let spans = self.machine.program().spans();
+        let pc = spans
+            .iter()
+            .position(|span| span.contains(&offset))
+            .or_else(|| spans.iter().position(|span| span.start >= offset));
+        let Some(pc) = pc else {
+            return "no instruction there or after it\n".to_string();
+        };
+        let mark = Mark::primary(spans[pc].clone(), "breakpoint");
+        self.machine.add_breakpoint(pc);
+        let mut reply = format!("breakpoint at instruction {pc}\n");
+        snippet(&mut reply, &self.name, &self.source, vec![mark]);
+        reply
+    }
+
+    /// Shows the next instruction and where in the source it comes from.
+    fn location(&self) -> String {
+        let pc = self.machine.pc();
+        let program = self.machine.program();
+        let Some(instr) = program.instrs().get(pc) else {
+            return "at the end of the program\n".to_string();
+        };
+        let pointer = self.machine.pointer();
+        let mut reply = format!("instruction {pc}: {instr:?}, pointer at {pointer}\n");
+        if let Some(span) = program.span(pc) {
+            snippet(
+                &mut reply,
+                &self.name,
+                &self.source,
+                vec![Mark::primary(span, "next")],
+            );
+        }
+        reply
+    }
+
+    /// Shows the cells up to `radius` away from the pointer, as far as the
+    /// tape has them.
+    fn tape(&self, radius: usize) -> String {
+        let pointer = self.machine.pointer();
+        let window = self.machine.window(radius as isize);
+        let first = *window.start();
+        let columns: Vec<_> = window
+            .map(|position| {
+                (
+                    position.to_string(),
+                    format!("{:?}", self.machine.cell(position)),
+                )
+            })
+            .collect();
+        let width = columns
+            .iter()
+            .map(|(position, value)| position.len().max(value.len()))
+            .max()
+            .unwrap_or(1);
+        let row = |column: fn(&(String, String)) -> &String| {
+            let cells: Vec<_> = columns
+                .iter()
+                .map(|c| format!("{:>width$}", column(c)))
+                .collect();
+            cells.join(" ")
+        };
+        let caret = (pointer - first) as usize * (width + 1) + width - 1;
+        format!(
+            "{}\n{}\n{}^\n",
+            row(|(position, _)| position),
+            row(|(_, value)| value),
+            " ".repeat(caret)
+        )
+    }
+
+    fn set(&mut self, position: &str, value: &str) -> String {
+        let Ok(position) = position.parse() else {
+            return format!("not a cell position: `{position}`\n");
+        };
+        let Ok(value) = value.parse::<C>() else {
+            return format!("not a cell value: `{value}`\n");
+        };
+        match self.machine.set_cell(position, value) {
+            Ok(()) => String::new(),
+            Err(e) => diagnostics::render(&e, &self.name, &self.source),
+        }
+    }
+}
+
+/// Byte offset of `line` and `column`, which start at 1 and count
+/// characters, like in `SourcePos`.
+fn offset_of(source: &str, line: usize, column: usize) -> Option<usize> {
+    let start = match line.checked_sub(2) {
+        None if line == 1 => 0,
+        None => return None,
+        Some(newlines) => source.match_indices('\n').nth(newlines)?.0 + 1,
+    };
+    source[start..]
+        .char_indices()
+        .take_while(|&(_, c)| c != '\n')
+        .nth(column.checked_sub(1)?)
+        .map(|(i, _)| start + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, ParseOptions, Program};
+
+    #[test]
+    fn session() {
+        let source = "++[>+<-]\n>.,";
+        let machine = Machine::new(
+            Program::parse_raw(source, ParseOptions::default()).unwrap(),
+            Config::default(),
+        )
+        .unwrap();
+        let mut debugger = Debugger::<u8>::new(machine, "test.bf", source);
+        let mut command = |line| debugger.command(line).unwrap();
+        assert_eq!(
+            command("b 2:2"),
+            "breakpoint at instruction 9
+ --> test.bf:2:2
+  |
+2 | >.,
+  |  ^ breakpoint
+"
+        );
+        assert_eq!(
+            command("n"),
+            "instruction 1: Add(1, 0), pointer at 0
+ --> test.bf:1:2
+  |
+1 | ++[>+<-]
+  |  ^ next
+"
+        );
+        assert!(command("s 1").starts_with("instruction 2: LoopBegin(7)"));
+        assert!(command("next").starts_with("instruction 8: Ptr(1)"));
+        assert_eq!(command("tape 2"), "0 1 2\n0 2 0\n^\n");
+        assert_eq!(command("set 1 65"), "");
+        assert!(command("set 1 x").starts_with("not a cell value"));
+        assert!(command("set 1 256").starts_with("not a cell value"));
+        assert!(command("set 1 -1").starts_with("not a cell value"));
+        assert!(command("tape -1").starts_with("not a number of cells"));
+        assert!(command("tape 99999").starts_with("at most"));
+        assert!(command("c").starts_with("breakpoint\ninstruction 9: Out(0)"));
+        assert!(command("c").starts_with("A\nprogram needs input"));
+        command("input hi");
+        assert_eq!(command("c"), "program halted after 16 steps\n");
+        assert!(command("frobnicate").starts_with("unknown command"));
+        assert!(command("b 3:1").starts_with("not a LINE:COLUMN"));
+        assert_eq!(debugger.command("q"), None);
+    }
+    #[test]
+    fn reverse() {
+        let source = "+++>+<[->+<]";
+        let machine = Machine::new(
+            Program::parse_raw(source, ParseOptions::default()).unwrap(),
+            Config::default(),
+        )
+        .unwrap();
+        let mut debugger = Debugger::<u8>::new(machine, "test.bf", source);
+        let mut command = |line| debugger.command(line).unwrap();
+        assert_eq!(command("c"), "program halted after 22 steps\n");
+        assert!(command("rc 0")
+            .starts_with("the next instruction last changed the cell\ninstruction 7: Add(-1, 0)"));
+        assert_eq!(command("tape 1"), "0 1\n1 3\n^\n");
+        assert!(command("rs 2").starts_with("instruction 10: Ptr(-1)"));
+        assert!(command("reverse-step").starts_with("instruction 9: Add(1, 0)"));
+        assert!(command("rc x").starts_with("not a cell position"));
+        assert!(command("rc").starts_with("back at the start\ninstruction 0: Add(1, 0)"));
+    }
+    #[test]
+    fn offsets() {
+        let source = "ab\n\nÿc\n";
+        assert_eq!(offset_of(source, 1, 2), Some(1));
+        assert_eq!(offset_of(source, 2, 1), None);
+        assert_eq!(offset_of(source, 3, 2), Some(6));
+        assert_eq!(offset_of(source, 3, 3), None);
+        assert_eq!(offset_of(source, 0, 1), None);
+    }
+}
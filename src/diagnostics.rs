@@ -0,0 +1,217 @@
+//! Error reports in the style of rustc, showing the offending source.
+
+use crate::{BfError, ParseErrorKind, SourcePos, Span};
+use std::fmt::Write;
+
+/// A labelled part of the source. The primary mark is underlined with `^`,
+/// secondary ones with `-`.
+pub(crate) struct Mark {
+    span: Span,
+    label: String,
+    primary: bool,
+}
+
+impl Mark {
+    pub(crate) fn primary(span: Span, label: impl Into<String>) -> Self {
+        Self {
+            span,
+            label: label.into(),
+            primary: true,
+        }
+    }
+
+    pub(crate) fn secondary(span: Span, label: impl Into<String>) -> Self {
+        Self {
+            span,
+            label: label.into(),
+            primary: false,
+        }
+    }
+}
+
+/// Renders `error` for the program `source`, read from the file `name`.
+/// Errors without a source location get a one-line report.
+pub fn render(error: &BfError, name: &str, source: &str) -> String {
+    let bracket = |pos: &SourcePos| pos.offset..pos.offset + 1;
+    let (message, marks) = match error {
+        BfError::Parse {
+            kind: ParseErrorKind::UnmatchedClose,
+            pos,
+            related,
+        } => {
+            let mut marks = vec![Mark::primary(bracket(pos), "no open `[` to close")];
+            if let [open, close] = &related[..] {
+                marks.push(Mark::secondary(
+                    bracket(open),
+                    "the last loop before it opens here",
+                ));
+                marks.push(Mark::secondary(bracket(close), "and is closed here"));
+            }
+            ("unmatched closing bracket (`]`)".to_string(), marks)
+        }
+        BfError::Parse {
+            kind: ParseErrorKind::UnmatchedOpen { unclosed },
+            pos,
+            related,
+        } => {
+            let message = match unclosed {
+                1 => "unmatched opening bracket (`[`)".to_string(),
+                n => format!("{n} unmatched opening brackets (`[`)"),
+            };
+            let mut marks: Vec<_> = related
+                .iter()
+                .map(|open| Mark::secondary(bracket(open), "also never closed"))
+                .collect();
+            marks.push(Mark::primary(bracket(pos), "never closed"));
+            (message, marks)
+        }
+        BfError::Runtime {
+            kind,
+            pc,
+            pointer,
+            cell,
+            span: Some(span),
+        } => (
+            kind.to_string(),
+            vec![Mark::primary(
+                span.clone(),
+                match cell {
+                    Some(cell) => format!(
+                        "at instruction {pc}, with the pointer at {pointer}, accessing cell {cell}"
+                    ),
+                    None => format!("at instruction {pc}, with the pointer at {pointer}"),
+                },
+            )],
+        ),
+        error => return format!("error: {error}\n"),
+    };
+    let mut out = format!("error: {message}\n");
+    snippet(&mut out, name, source, marks);
+    out
+}
+
+/// Writes the lines of `source` that `marks` are on, with the marks
+/// underlined below them.
+pub(crate) fn snippet(out: &mut String, name: &str, source: &str, mut marks: Vec<Mark>) {
+    for mark in &mut marks {
+        mark.span.end = mark.span.end.min(source.len());
+        mark.span.start = mark.span.start.min(mark.span.end);
+    }
+    marks.sort_by_key(|mark| mark.span.start);
+    let position = |mark: &Mark| SourcePos::new(source, mark.span.start);
+    let primary = marks.iter().find(|mark| mark.primary).unwrap_or(&marks[0]);
+    let pos = position(primary);
+    let width = marks
+        .iter()
+        .map(|mark| position(mark).line)
+        .max()
+        .unwrap_or(1)
+        .to_string()
+        .len();
+    let gutter = " ".repeat(width);
+    writeln!(out, "{gutter}--> {name}:{}:{}", pos.line, pos.column).unwrap();
+    writeln!(out, "{gutter} |").unwrap();
+    let mut last_line = None;
+    for mark in &marks {
+        let pos = position(mark);
+        if last_line != Some(pos.line) {
+            if last_line.is_some_and(|line| line + 1 < pos.line) {
+                writeln!(out, "...").unwrap();
+            }
+            let line = line_of(source, mark.span.start).replace('\t', " ");
+            writeln!(out, "{:>width$} | {}", pos.line, line.trim_end()).unwrap();
+            last_line = Some(pos.line);
+        }
+        let rest = source[mark.span.start..].lines().next().unwrap_or("");
+        let underlined = source[mark.span.clone()]
+            .chars()
+            .zip(rest.chars())
+            .count()
+            .max(1);
+        let underline = if mark.primary { "^" } else { "-" }.repeat(underlined);
+        let indent = " ".repeat(pos.column - 1);
+        writeln!(out, "{gutter} | {indent}{underline} {}", mark.label).unwrap();
+    }
+}
+
+/// The line of `source` containing byte `offset`, without its line break.
+fn line_of(source: &str, offset: usize) -> &str {
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..]
+        .find('\n')
+        .map_or(source.len(), |i| offset + i);
+    &source[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, OptLevel, Program};
+
+    #[test]
+    fn parse_errors() {
+        let source = "+[\n  -[>]]<]\n[";
+        let error = Program::parse(source).unwrap_err();
+        assert_eq!(
+            render(&error, "test.bf", source),
+            "error: unmatched closing bracket (`]`)
+ --> test.bf:2:9
+  |
+1 | +[
+  |  - the last loop before it opens here
+2 |   -[>]]<]
+  |       - and is closed here
+  |         ^ no open `[` to close
+"
+        );
+        let source = "é]";
+        let error = Program::parse(source).unwrap_err();
+        assert_eq!(
+            render(&error, "test.bf", source),
+            "error: unmatched closing bracket (`]`)
+ --> test.bf:1:2
+  |
+1 | é]
+  |  ^ no open `[` to close
+"
+        );
+        let source = "[[\n\n\n ÿ [-]\t[";
+        let error = Program::parse(source).unwrap_err();
+        assert_eq!(
+            render(&error, "test.bf", source),
+            "error: 3 unmatched opening brackets (`[`)
+ --> test.bf:4:8
+  |
+1 | [[
+  | - also never closed
+  |  - also never closed
+...
+4 |  ÿ [-] [
+  |        ^ never closed
+"
+        );
+    }
+    #[test]
+    fn runtime_errors() {
+        let source = "+\n>>+[<<<\n]";
+        let error = Program::parse(source)
+            .unwrap()
+            .optimize(OptLevel::O3)
+            .run_with(&Config::default(), &mut "".as_bytes(), &mut vec![])
+            .unwrap_err();
+        assert_eq!(
+            render(&error, "test.bf", source),
+            "error: BF pointer underflow
+ --> test.bf:2:4
+  |
+2 | >>+[<<<
+  |    ^^^^ at instruction 3, with the pointer at 2, accessing cell -1
+"
+        );
+        let error = BfError::InvalidConfig("tape size must not be 0");
+        assert_eq!(
+            render(&error, "test.bf", ""),
+            "error: Invalid configuration: tape size must not be 0\n"
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{Span, TapeError};
+use std::{fmt, io};
+
+/// Everything that can go wrong parsing or running a program.
+#[derive(Debug)]
+pub enum BfError {
+    /// The source has unbalanced brackets
+    Parse {
+        kind: ParseErrorKind,
+        /// Location of the offending bracket
+        pos: SourcePos,
+        /// For an unmatched `]`, both brackets of the loop closed last
+        /// before it; for unmatched `[`, the other unclosed ones, outermost
+        /// first
+        related: Vec<SourcePos>,
+    },
+    /// Execution stopped at instruction `pc` with the pointer at cell
+    /// `pointer`
+    Runtime {
+        kind: RuntimeErrorKind,
+        pc: usize,
+        pointer: isize,
+        /// For tape errors, the cell the instruction reached for, which
+        /// optimized instructions find at an offset from the pointer
+        cell: Option<isize>,
+        /// Source of instruction `pc`, if known
+        span: Option<Span>,
+    },
+    /// Reading input or writing output failed
+    Io(io::Error),
+    /// The `Config` cannot be run with
+    InvalidConfig(&'static str),
+    /// The `Snapshot` cannot be resumed with the program and `Config`
+    InvalidSnapshot(&'static str),
+    /// A deserialized `Program` is not one parsing could have made
+    InvalidProgram(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// A `]` without a `[` before it
+    UnmatchedClose,
+    /// A `[` without a `]` after it; `unclosed` counts every such `[`
+    UnmatchedOpen { unclosed: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    Tape(TapeError),
+    /// A non-wrapping cell went out of range
+    CellOverflow,
+    /// `.` in Unicode output mode on a cell that holds no code point
+    NotUnicode,
+    /// The program took `Config::max_steps` steps; the instruction at `pc`
+    /// has not run yet
+    OutOfFuel,
+    /// The `Config::cancel` token was cancelled
+    Cancelled,
+    /// The program ran for longer than `Config::timeout`
+    TimedOut,
+}
+
+/// A location in the source text. `line` and `column` start at 1, and
+/// `column` counts characters rather than bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcePos {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourcePos {
+    /// Position of byte `offset` of `source`.
+    pub fn new(source: &str, offset: usize) -> Self {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl fmt::Display for SourcePos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnmatchedClose => write!(f, "Unmatched closing bracket (`]`)"),
+            ParseErrorKind::UnmatchedOpen { unclosed: 1 } => {
+                write!(f, "Unmatched opening bracket (`[`)")
+            }
+            ParseErrorKind::UnmatchedOpen { unclosed } => {
+                write!(f, "{unclosed} unmatched opening brackets (`[`), last one")
+            }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::Tape(e) => write!(f, "{e}"),
+            RuntimeErrorKind::CellOverflow => write!(f, "BF cell overflow"),
+            RuntimeErrorKind::NotUnicode => write!(f, "BF cell is not a Unicode scalar value"),
+            RuntimeErrorKind::OutOfFuel => write!(f, "BF step limit exceeded"),
+            RuntimeErrorKind::Cancelled => write!(f, "BF program cancelled"),
+            RuntimeErrorKind::TimedOut => write!(f, "BF program timed out"),
+        }
+    }
+}
+
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BfError::Parse { kind, pos, .. } => write!(f, "{kind} at {pos}"),
+            BfError::Runtime {
+                kind,
+                pc,
+                pointer,
+                cell,
+                span,
+            } => {
+                write!(f, "{kind} at instruction {pc}")?;
+                if let Some(span) = span {
+                    write!(f, ", source bytes {}..{}", span.start, span.end)?;
+                }
+                write!(f, " (pointer at {pointer}")?;
+                if let Some(cell) = cell {
+                    write!(f, ", accessing cell {cell}")?;
+                }
+                write!(f, ")")
+            }
+            BfError::Io(e) => write!(f, "I/O error: {e}"),
+            BfError::InvalidConfig(reason) => write!(f, "Invalid configuration: {reason}"),
+            BfError::InvalidSnapshot(reason) => write!(f, "Invalid snapshot: {reason}"),
+            BfError::InvalidProgram(reason) => write!(f, "Invalid program: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for BfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BfError::Io(e) => Some(e),
+            BfError::Runtime {
+                kind: RuntimeErrorKind::Tape(e),
+                ..
+            } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BfError {
+    fn from(e: io::Error) -> Self {
+        BfError::Io(e)
+    }
+}
+
+impl From<TapeError> for RuntimeErrorKind {
+    fn from(e: TapeError) -> Self {
+        RuntimeErrorKind::Tape(e)
+    }
+}
@@ -1,153 +1,358 @@
-use anyhow::{anyhow, bail, Result};
-use itertools::Itertools;
-use std::io::{BufWriter, Read, Write};
-use serde::{Serialize, Deserialize};
-
-const INITIAL_CAPACITY: usize = 30000;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-enum Instr {
-    Add(u8),          // +    Use complement to subtract (i.e. -2==+254 (mod 256))
-    Ptr(isize),       // > and <
-    LoopBegin(usize), // [    Index after matching ]
-    LoopEnd(usize),   // ]    Index after matching [
-    Out,
-    In,
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod cell;
+pub mod debugger;
+pub mod diagnostics;
+mod error;
+mod machine;
+pub mod optimizer;
+pub mod profiler;
+mod tape;
+pub mod trace;
+
+pub use cell::{Cell, CellWidth};
+pub use error::{BfError, ParseErrorKind, RuntimeErrorKind, SourcePos};
+pub use machine::{Machine, Snapshot, Status};
+pub use optimizer::OptLevel;
+pub use tape::{TapeError, TapePolicy};
+
+/// A single instruction. The `isize` of `Add`, `Set`, `Out` and `In` is an
+/// offset from the pointer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Instr {
+    Add(i64, isize),           // + and -
+    Ptr(isize),                // > and <
+    LoopBegin(usize),          // [    Index after matching ]
+    LoopEnd(usize),            // ]    Index after matching [
+    Set(i64, isize),           // [-]  Clear loop, plus any directly following +/-
+    MulAdd(Vec<(isize, i64)>), // [->+>++<<]  Add cell*factor at each offset, then clear
+    Scan(isize),               // [>]  Move by stride until a zero cell is found
+    Out(isize),
+    In(isize),
+    Debug, // #    Dump the tape, if enabled by `ParseOptions`
+}
+
+/// How to run a `Program`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    pub cell_width: CellWidth,
+    pub eof: EofBehavior,
+    pub tape: TapePolicy,
+    /// Most bytes the tape may take up, unbounded if `None`
+    pub memory_limit: Option<usize>,
+    pub output: OutputMode,
+    /// Most steps the program may take, unbounded if `None`. Each
+    /// instruction takes one step, plus one per cell moved for `Scan` and
+    /// per cell changed for `MulAdd`.
+    pub max_steps: Option<u64>,
+    /// Longest the program may run for, unbounded if `None`
+    pub timeout: Option<Duration>,
+    /// Stops the program once cancelled
+    pub cancel: Option<CancelToken>,
+    /// Cells on each side of the pointer that `Instr::Debug` shows, 8 if
+    /// `None`; at most `MAX_DEBUG_WINDOW`
+    pub debug_window: Option<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Most cells on each side of the pointer a tape dump may show.
+pub const MAX_DEBUG_WINDOW: usize = 1 << 12;
+
+/// Extensions of the language to parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Parse `#` into `Instr::Debug`, which dumps the tape around the
+    /// pointer; otherwise it is a comment like any other character
+    pub debug: bool,
+}
+
+/// Number of instructions run between checks of `Config::timeout` and
+/// `Config::cancel`.
+const INTERRUPT_INTERVAL: u32 = 1 << 12;
+
+/// A flag for stopping a run from another thread. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes every run using this token fail with `Cancelled` soon.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Tokens are equal if they share their flag.
+impl PartialEq for CancelToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancelToken {}
+
+/// How `.` turns a cell into output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum OutputMode {
+    /// Write the low byte of the cell as is
+    #[default]
+    Bytes,
+    /// Write the UTF-8 encoding of the character whose code point is in the
+    /// cell; other values are an error
+    Unicode,
+}
+
+/// What `,` does once the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum EofBehavior {
+    /// Store 0
+    #[default]
+    Zero,
+    /// Store -1, i.e. all bits set (255 for 8-bit cells)
+    MinusOne,
+    /// Leave the cell as it is
+    Unchanged,
+}
+
+/// Byte range of the source text an instruction was made from.
+pub type Span = Range<usize>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "UncheckedProgram")]
 pub struct Program {
     instrs: Vec<Instr>,
+    /// Where in the source each instruction comes from, indexed like
+    /// `instrs`
+    spans: Vec<Span>,
 }
 
-impl Program {
-    pub fn parse(source: &str) -> Result<Self> {
-        let mut program: Vec<Instr> = source
-            .chars()
-            .filter_map(|c| match c {
-                '+' => Some(Instr::Add(1)),
-                '-' => Some(Instr::Add(0u8.wrapping_sub(1))),
-                '>' => Some(Instr::Ptr(1)),
-                '<' => Some(Instr::Ptr(-1)),
-                '[' => Some(Instr::LoopBegin(0)),
-                ']' => Some(Instr::LoopEnd(0)),
-                '.' => Some(Instr::Out),
-                ',' => Some(Instr::In),
-                _ => None,
-            })
-            .coalesce(|a, b| match (a, b) {
-                (Instr::Add(c), Instr::Add(d)) => Ok(Instr::Add(c.wrapping_add(d))),
-                (Instr::Ptr(c), Instr::Ptr(d)) => Ok(Instr::Ptr(c + d)),
-                _ => Err((a, b)),
-            })
-            .collect(); // loosely inspired by https://stackoverflow.com/a/32717990
-
-        let mut jump_stack = Vec::new();
-
-        for i in 0..program.len() {
-            match program[i] {
-                Instr::LoopBegin(_) => jump_stack.push(i),
-                Instr::LoopEnd(_) => {
-                    let other = jump_stack.pop().ok_or(anyhow!(
-                        "Unmatched closing bracket (`}}`) at position {}",
-                        i
-                    ))?;
-                    // DO jump to matching bracket, as post-increment will
-                    // jump to instruction after that to skip an unnecessary
-                    // comparison
-                    program[i] = Instr::LoopEnd(other);
-                    program[other] = Instr::LoopBegin(i);
-                }
-                _ => (),
-            }
-        }
+/// A `Program` as deserialized, before it is checked to be one that
+/// `Program::parse_raw` and the optimizer could have made.
+#[derive(Deserialize)]
+struct UncheckedProgram {
+    instrs: Vec<Instr>,
+    spans: Vec<Span>,
+}
 
-        let len = jump_stack.len();
-        if len != 0 {
-            bail!("{} unmatched opening brackets (`{{`)", len);
+impl TryFrom<UncheckedProgram> for Program {
+    type Error = BfError;
+
+    fn try_from(program: UncheckedProgram) -> Result<Self, BfError> {
+        let UncheckedProgram { instrs, spans } = program;
+        if spans.len() != instrs.len() {
+            return Err(BfError::InvalidProgram("not one span per instruction"));
+        }
+        let mut linked = instrs.clone();
+        if link_loops(&mut linked).is_err() || linked != instrs {
+            return Err(BfError::InvalidProgram("loops are not linked"));
         }
+        if instrs.contains(&Instr::Scan(0)) {
+            return Err(BfError::InvalidProgram("scan without a stride"));
+        }
+        Ok(Self { instrs, spans })
+    }
+}
+
+impl Program {
+    /// Parses `source`, merging runs of `+`/`-` and of `>`/`<` as
+    /// `OptLevel::O1` does; see `optimize` for anything smarter.
+    pub fn parse(source: &str) -> Result<Self, BfError> {
+        Self::parse_with(source, ParseOptions::default())
+    }
+
+    /// Like `parse`, with the extensions of `options`.
+    pub fn parse_with(source: &str, options: ParseOptions) -> Result<Self, BfError> {
+        Ok(Self::parse_raw(source, options)?.optimize(OptLevel::O1))
+    }
+
+    /// Parses `source` into one instruction per command character, the
+    /// `OptLevel::O0` program the optimizer starts from.
+    pub fn parse_raw(source: &str, options: ParseOptions) -> Result<Self, BfError> {
+        let (mut program, spans) = lex(source, options);
+        link_loops(&mut program).map_err(|(kind, i, related)| BfError::Parse {
+            kind,
+            pos: SourcePos::new(source, spans[i].start),
+            related: related
+                .into_iter()
+                .map(|j| SourcePos::new(source, spans[j].start))
+                .collect(),
+        })?;
+
+        Ok(Self {
+            instrs: program,
+            spans,
+        })
+    }
+
+    /// Runs the passes of `level`. Use `optimizer::Optimizer` directly to
+    /// pick individual passes. Passes are never undone, so only a program
+    /// from `parse_raw` can be had at `OptLevel::O0`.
+    pub fn optimize(self, level: OptLevel) -> Self {
+        optimizer::Optimizer::new(level).run(self)
+    }
+
+    pub fn instrs(&self) -> &[Instr] {
+        &self.instrs
+    }
+
+    /// Source spans of all instructions, see `span`.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
+    }
+
+    /// The part of the source instruction `pc` was made from. For an
+    /// optimized instruction this covers everything it replaces.
+    pub fn span(&self, pc: usize) -> Option<Span> {
+        self.spans.get(pc).cloned()
+    }
+
+    /// Runs with the default `Config`, i.e. on wrapping 8-bit cells.
+    pub fn run(self, input: &mut impl Read, output: &mut impl Write) -> Result<(), BfError> {
+        self.run_with(&Config::default(), input, output)
+    }
+
+    pub fn run_with(
+        self,
+        config: &Config,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), BfError> {
+        with_cell_width!(config.cell_width, C => self.execute::<C>(config, input, output))
+    }
+
+    fn execute<C: Cell>(
+        self,
+        config: &Config,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), BfError> {
+        Machine::<C>::new(self, config.clone())?.run(input, output)
+    }
+}
+
+/// Translates source characters to instructions, along with the span of
+/// each. Jump targets are left unset.
+fn lex(source: &str, options: ParseOptions) -> (Vec<Instr>, Vec<Span>) {
+    source
+        .char_indices()
+        .filter_map(|(offset, c)| {
+            let instr = match c {
+                '+' => Instr::Add(1, 0),
+                '-' => Instr::Add(-1, 0),
+                '>' => Instr::Ptr(1),
+                '<' => Instr::Ptr(-1),
+                '[' => Instr::LoopBegin(0),
+                ']' => Instr::LoopEnd(0),
+                '.' => Instr::Out(0),
+                ',' => Instr::In(0),
+                '#' if options.debug => Instr::Debug,
+                _ => return None,
+            };
+            Some((instr, offset..offset + 1))
+        })
+        .unzip()
+}
+
+/// Fills in the jump targets of every `LoopBegin`/`LoopEnd` pair. On failure
+/// returns the index of the offending bracket and of the brackets explaining
+/// it, see `BfError::Parse`.
+fn link_loops(program: &mut [Instr]) -> Result<(), (ParseErrorKind, usize, Vec<usize>)> {
+    let mut jump_stack = Vec::new();
+    let mut last_closed = None;
 
-        Ok(Self { instrs: program })
-    }
-
-    pub fn run(self, input: &mut impl Read, output: &mut impl Write) -> Result<()> {
-        let mut mem = vec![0u8; INITIAL_CAPACITY];
-        let mut ptr: usize = 0;
-        let mut pc: usize = 0;
-        let mut writer = BufWriter::new(output);
-        let mut input = input.bytes();
-        while pc < self.instrs.len() {
-            match self.instrs[pc] {
-                Instr::Add(x) => mem[ptr] = mem[ptr].wrapping_add(x),
-                Instr::Ptr(x) => {
-                    if x >= 0 {
-                        let Some(y) = ptr.checked_add(x as usize) else {
-                            bail!("BF pointer overflow");
-                        };
-                        ptr = y;
-                    } else {
-                        let Some(y) = ptr.checked_sub((-x) as usize) else {
-                            bail!("BF pointer underflow");
-                        };
-                        ptr = y;
-                    }
-                    if ptr >= mem.len() {
-                        mem.resize(mem.len() + 1, 0);
-                    }
-                }
-                Instr::LoopBegin(x) => {
-                    if mem[ptr] == 0 {
-                        pc = x;
-                    }
-                }
-                Instr::LoopEnd(x) => {
-                    if mem[ptr] != 0 {
-                        pc = x;
-                    }
-                }
-                Instr::Out => write!(writer, "{}", mem[ptr] as char).unwrap(),
-                Instr::In => match input.next() {
-                    Some(Ok(v)) => mem[ptr] = v,
-                    Some(Err(_)) => bail!("Read error!"),
-                    None => mem[ptr] = 0,
-                },
+    for i in 0..program.len() {
+        match program[i] {
+            Instr::LoopBegin(_) => jump_stack.push(i),
+            Instr::LoopEnd(_) => {
+                let Some(other) = jump_stack.pop() else {
+                    let related = last_closed.map_or(vec![], |(open, close)| vec![open, close]);
+                    return Err((ParseErrorKind::UnmatchedClose, i, related));
+                };
+                last_closed = Some((other, i));
+                // DO jump to matching bracket, as post-increment will
+                // jump to instruction after that to skip an unnecessary
+                // comparison
+                program[i] = Instr::LoopEnd(other);
+                program[other] = Instr::LoopBegin(i);
             }
-            pc += 1;
+            _ => (),
         }
-        Ok(())
     }
+
+    if let Some(last) = jump_stack.pop() {
+        let unclosed = jump_stack.len() + 1;
+        return Err((ParseErrorKind::UnmatchedOpen { unclosed }, last, jump_stack));
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::{Instr::*, *};
+    use std::time::Instant;
 
     #[test]
     fn parse_1() {
-        assert_eq!(Program::parse("+++").unwrap().instrs, vec![Add(3)]);
+        assert_eq!(Program::parse("+++").unwrap().instrs, vec![Add(3, 0)]);
     }
     #[test]
     fn parse_2() {
-        assert_eq!(
-            Program::parse("---").unwrap().instrs,
-            vec![Add(0u8.wrapping_sub(3))]
-        );
+        assert_eq!(Program::parse("---").unwrap().instrs, vec![Add(-3, 0)]);
     }
     #[test]
     fn parse_3() {
         assert_eq!(
             Program::parse("++>>[--<<]").unwrap().instrs,
             vec![
-                Add(2),
+                Add(2, 0),
                 Ptr(2),
                 LoopBegin(5),
-                Add(0u8.wrapping_sub(2)),
+                Add(-2, 0),
                 Ptr(-2),
                 LoopEnd(2)
             ]
         );
     }
     #[test]
+    fn parse_errors() {
+        let source = "+[\n  -[>]]<]\n[";
+        let Err(BfError::Parse { kind, pos, related }) = Program::parse(source) else {
+            panic!("expected a parse error");
+        };
+        assert_eq!(kind, ParseErrorKind::UnmatchedClose);
+        assert_eq!(
+            pos,
+            SourcePos {
+                offset: 11,
+                line: 2,
+                column: 9
+            }
+        );
+        let offsets: Vec<_> = related.iter().map(|pos| pos.offset).collect();
+        assert_eq!(offsets, [1, 9]);
+        let err = Program::parse("[[\n ÿ [-]\t[").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "3 unmatched opening brackets (`[`), last one at line 2, column 8"
+        );
+        assert_eq!(
+            Program::parse("[").unwrap_err().to_string(),
+            "Unmatched opening bracket (`[`) at line 1, column 1"
+        );
+    }
+    #[test]
     fn hello_world() {
         let mut buf = Vec::new();
         Program::parse(
@@ -165,11 +370,325 @@ mod tests {
         assert_eq!(buf, "Hello World!".as_bytes());
     }
     #[test]
+    fn set_cell() {
+        let mut buf = Vec::new();
+        Program::parse("+++++[-]++++++++[>++++++++<-]>+.[-]++++++++++.")
+            .unwrap()
+            .run(&mut "".as_bytes(), &mut buf)
+            .unwrap();
+        assert_eq!(buf, "A\n".as_bytes());
+    }
+    #[test]
+    fn scan_past_end() {
+        let mut buf = Vec::new();
+        let fill = "+>".repeat(tape::DEFAULT_SIZE + 10);
+        Program::parse(&format!(">{fill}+<[<]>[>]++.<."))
+            .unwrap()
+            .run(&mut "".as_bytes(), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [2, 1]);
+    }
+    #[test]
+    fn offset_underflow() {
+        let mut buf = Vec::new();
+        assert!(Program::parse(">+<<.>")
+            .unwrap()
+            .run(&mut "".as_bytes(), &mut buf)
+            .is_err());
+    }
+    fn run_with(source: &str, cell_width: CellWidth) -> Result<Vec<u8>, BfError> {
+        run_config(
+            source,
+            "",
+            &Config {
+                cell_width,
+                ..Config::default()
+            },
+        )
+    }
+    fn run_config(source: &str, input: &str, config: &Config) -> Result<Vec<u8>, BfError> {
+        let mut buf = Vec::new();
+        Program::parse(source)?.optimize(OptLevel::O3).run_with(
+            config,
+            &mut input.as_bytes(),
+            &mut buf,
+        )?;
+        Ok(buf)
+    }
+    #[test]
+    fn cell_widths() {
+        let source = format!("{}[>+<[-]]>.", "+".repeat(256));
+        assert_eq!(run_with(&source, CellWidth::U8).unwrap(), [0]);
+        assert_eq!(run_with(&source, CellWidth::U16).unwrap(), [1]);
+        assert_eq!(run_with(&source, CellWidth::I64).unwrap(), [1]);
+        let source = format!("+{}[>+<[-]]>.", "[->++<]>[-<+>]<".repeat(16));
+        assert_eq!(run_with(&source, CellWidth::U16).unwrap(), [0]);
+        assert_eq!(run_with(&source, CellWidth::U32).unwrap(), [1]);
+        assert_eq!(run_with("+++.", CellWidth::U64).unwrap(), [3]);
+    }
+    #[test]
+    fn cell_overflow() {
+        let double = "[->++<]>[-<+>]<";
+        let source = format!("+{}", double.repeat(62));
+        assert!(run_with(&source, CellWidth::I64).is_ok());
+        assert!(run_with(&format!("{source}{double}"), CellWidth::I64).is_err());
+        assert!(run_with(&format!("-{source}{double}"), CellWidth::I64).is_ok());
+    }
+    #[test]
+    fn eof() {
+        let source = "+++,+.>,+.";
+        let with = |eof, cell_width| Config {
+            cell_width,
+            eof,
+            ..Config::default()
+        };
+        let zero = with(EofBehavior::Zero, CellWidth::U8);
+        assert_eq!(run_config(source, "", &zero).unwrap(), [1, 1]);
+        assert_eq!(run_config(source, "a", &zero).unwrap(), [b'b', 1]);
+        let minus_one = with(EofBehavior::MinusOne, CellWidth::U8);
+        assert_eq!(run_config(source, "", &minus_one).unwrap(), [0, 0]);
+        let minus_one = with(EofBehavior::MinusOne, CellWidth::I64);
+        assert_eq!(run_config(source, "", &minus_one).unwrap(), [0, 0]);
+        let unchanged = with(EofBehavior::Unchanged, CellWidth::U8);
+        assert_eq!(run_config(source, "", &unchanged).unwrap(), [4, 1]);
+    }
+    #[test]
+    fn runtime_errors() {
+        let Err(BfError::Runtime {
+            kind,
+            pc,
+            pointer,
+            cell,
+            span,
+        }) = run_config(">>+[<<<]", "", &Config::default())
+        else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(kind, RuntimeErrorKind::Tape(TapeError::Underflow));
+        assert_eq!((pc, pointer, cell, span), (2, 2, Some(-1), Some(3..8)));
+        let source = format!("+{}", "[->++<]>[-<+>]<".repeat(63));
+        let Err(BfError::Runtime { kind, .. }) = run_with(&source, CellWidth::I64) else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(kind, RuntimeErrorKind::CellOverflow);
+    }
+    #[test]
+    fn max_steps() {
+        let with = |max_steps| Config {
+            max_steps: Some(max_steps),
+            ..Config::default()
+        };
+        let source = "+>+>+>+<<<[>].";
+        assert_eq!(run_config(source, "", &with(10)).unwrap(), [0]);
+        let out_of_fuel = |max_steps| match run_config(source, "", &with(max_steps)) {
+            Err(BfError::Runtime {
+                kind: RuntimeErrorKind::OutOfFuel,
+                pc,
+                pointer,
+                ..
+            }) => (pc, pointer),
+            result => panic!("expected to run out of fuel, got {result:?}"),
+        };
+        assert_eq!(out_of_fuel(9), (5, 4));
+        assert_eq!(out_of_fuel(4), (4, 0));
+        assert_eq!(out_of_fuel(0), (0, 0));
+        assert!(run_config("+[]", "", &with(1000)).is_err());
+    }
+    #[test]
+    fn interrupts() {
+        let cancel = CancelToken::new();
+        let config = Config {
+            cancel: Some(cancel.clone()),
+            ..Config::default()
+        };
+        let run = move || run_config("+[]", "", &config);
+        cancel.cancel();
+        assert!(matches!(
+            run(),
+            Err(BfError::Runtime {
+                kind: RuntimeErrorKind::Cancelled,
+                pc: 0,
+                ..
+            })
+        ));
+        let cancel = CancelToken::new();
+        let config = Config {
+            cancel: Some(cancel.clone()),
+            ..Config::default()
+        };
+        let running = std::thread::spawn(move || run_config("+[]", "", &config));
+        std::thread::sleep(Duration::from_millis(10));
+        cancel.cancel();
+        assert!(matches!(
+            running.join().unwrap(),
+            Err(BfError::Runtime {
+                kind: RuntimeErrorKind::Cancelled,
+                ..
+            })
+        ));
+        // However fast the machine, the run outlasts the chunks `run` runs
+        // it in, and only a timeout counted from its start stops it
+        let config = Config {
+            timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        let timed = config.clone();
+        std::thread::spawn(move || sender.send(run_config("+[]", "", &timed)));
+        let result = receiver.recv_timeout(Duration::from_secs(30));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(
+            result.expect("the timeout never fired"),
+            Err(BfError::Runtime {
+                kind: RuntimeErrorKind::TimedOut,
+                ..
+            })
+        ));
+        assert!(run_config("+.", "", &config).is_ok());
+    }
+    #[test]
+    fn tape_policies() {
+        let with = |tape| Config {
+            tape,
+            ..Config::default()
+        };
+        let source = "<<<+++.>>>+.";
+        assert!(run_config(source, "", &with(TapePolicy::Growable)).is_err());
+        assert_eq!(
+            run_config(source, "", &with(TapePolicy::Infinite)).unwrap(),
+            [3, 1]
+        );
+        let source = "+<+<+<+[<]+.>>>>.";
+        assert_eq!(
+            run_config(source, "", &with(TapePolicy::Infinite)).unwrap(),
+            [1, 1]
+        );
+        assert_eq!(
+            run_config("<+>>>>>.", "", &with(TapePolicy::Circular(5))).unwrap(),
+            [1]
+        );
+        assert_eq!(
+            run_config("+>+>+>+<<<[>]<<<<.", "", &with(TapePolicy::Circular(5))).unwrap(),
+            [1]
+        );
+        assert!(run_config(">>>>.", "", &with(TapePolicy::Fixed(5))).is_ok());
+        let err = run_config(">>>>>.", "", &with(TapePolicy::Fixed(5))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "BF pointer out of bounds at instruction 0, source bytes 5..6 (pointer at 0, accessing cell 5)"
+        );
+        let err = Program::parse_raw(">>>>>.", ParseOptions::default())
+            .unwrap()
+            .run_with(&with(TapePolicy::Fixed(5)), &mut "".as_bytes(), &mut vec![])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "BF pointer out of bounds at instruction 4, source bytes 4..5 (pointer at 4, accessing cell 5)"
+        );
+        assert!(run_config("+>+>+>+>+[>]", "", &with(TapePolicy::Fixed(5))).is_err());
+        // A ring with no zero cell: the scan must not hide from the limits
+        let ring = Config {
+            max_steps: Some(1000),
+            ..with(TapePolicy::Circular(5))
+        };
+        let err = run_config("+>+>+>+>+[>]", "", &ring).unwrap_err();
+        assert!(matches!(
+            err,
+            BfError::Runtime {
+                kind: RuntimeErrorKind::OutOfFuel,
+                span: Some(ref span),
+                ..
+            } if *span == (9..12)
+        ));
+        assert!(run_config(".", "", &with(TapePolicy::Fixed(0))).is_err());
+    }
+    #[test]
+    fn long_jumps() {
+        let jump = ">".repeat(100_000);
+        let source = format!("{jump}+.{jump}.");
+        assert_eq!(run_config(&source, "", &Config::default()).unwrap(), [1, 0]);
+        let limited = Config {
+            memory_limit: Some(150_000),
+            ..Config::default()
+        };
+        assert_eq!(
+            run_config(&source, "", &limited).unwrap_err().to_string(),
+            "BF tape exceeds the memory limit at instruction 2, source bytes 200002..200003 (pointer at 0, accessing cell 200000)"
+        );
+    }
+    #[test]
+    fn output_modes() {
+        let minus_one = "-.";
+        assert_eq!(
+            run_config(minus_one, "", &Config::default()).unwrap(),
+            [255]
+        );
+        let unicode = |cell_width| Config {
+            cell_width,
+            output: OutputMode::Unicode,
+            ..Config::default()
+        };
+        assert_eq!(
+            run_config(minus_one, "", &unicode(CellWidth::U8)).unwrap(),
+            "ÿ".as_bytes()
+        );
+        let crab = format!("{}.", "+".repeat(0x1F980));
+        assert_eq!(
+            run_config(&crab, "", &unicode(CellWidth::U32)).unwrap(),
+            "🦀".as_bytes()
+        );
+        assert!(run_config(minus_one, "", &unicode(CellWidth::U32)).is_err());
+        assert!(run_config(minus_one, "", &unicode(CellWidth::I64)).is_err());
+    }
+    #[test]
+    fn debug_instr() {
+        let source = "+>++#<[-]#";
+        let options = ParseOptions { debug: true };
+        let program = Program::parse_with(source, options).unwrap();
+        assert_eq!(program.instrs()[3], Debug);
+        assert!(!Program::parse(source).unwrap().instrs().contains(&Debug));
+        let config = Config {
+            debug_window: Some(2),
+            ..Config::default()
+        };
+        let (mut output, mut debug) = (Vec::new(), Vec::new());
+        Machine::<u8>::new(program.optimize(OptLevel::O3), config)
+            .unwrap()
+            .run_with_debug(&mut "".as_bytes(), &mut output, &mut debug)
+            .unwrap();
+        assert!(output.is_empty());
+        assert_eq!(
+            String::from_utf8(debug).unwrap(),
+            "# instruction 3, pointer at 1, cells 0..=3: 1 [2] 0 0\n\
+             # instruction 6, pointer at 0, cells 0..=2: [0] 2 0\n"
+        );
+        let wide = Config {
+            debug_window: Some(usize::MAX),
+            ..Config::default()
+        };
+        assert!(matches!(
+            Machine::<u8>::new(Program::parse(source).unwrap(), wide),
+            Err(BfError::InvalidConfig(_))
+        ));
+    }
+    #[test]
     fn ser_de() {
         let prog = Program::parse(
             "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++."
-        ).unwrap();
+        ).unwrap().optimize(OptLevel::O3);
         let serialised = serde_json::to_string(&prog).unwrap();
         assert_eq!(serde_json::from_str::<Program>(&serialised).unwrap(), prog);
+
+        let span = r#"{"start":0,"end":1}"#;
+        for json in [
+            r#"{"instrs":[{"Add":[1,0]},{"Out":0}],"spans":[]}"#.to_string(),
+            format!(r#"{{"instrs":[{{"LoopBegin":5}},{{"LoopEnd":0}}],"spans":[{span},{span}]}}"#),
+            format!(r#"{{"instrs":[{{"LoopEnd":1}},{{"LoopBegin":0}}],"spans":[{span},{span}]}}"#),
+            format!(r#"{{"instrs":[{{"Scan":0}}],"spans":[{span}]}}"#),
+        ] {
+            let error = serde_json::from_str::<Program>(&json).unwrap_err();
+            assert!(error.to_string().starts_with("Invalid program"), "{json}");
+        }
     }
 }
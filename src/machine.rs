@@ -0,0 +1,1285 @@
+use crate::tape::Tape;
+use crate::trace::{self, TraceEvent, TraceOptions};
+use crate::{
+    BfError, CancelToken, Cell, CellWidth, Config, EofBehavior, Instr, OutputMode, Program,
+    RuntimeErrorKind, TapeError, TapePolicy, INTERRUPT_INTERVAL, MAX_DEBUG_WINDOW,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+/// Number of steps `Machine::run` runs between writes of the output.
+const RUN_CHUNK: u64 = 1 << 16;
+
+/// Cells on each side of the pointer `Instr::Debug` shows by default.
+const DEBUG_WINDOW: usize = 8;
+
+/// Instructions run between the snapshots of a `History`, fewer in tests
+/// so that they run into `MAX_CHECKPOINTS` quickly.
+const CHECKPOINT_INTERVAL: u64 = if cfg!(test) { 1 << 10 } else { 1 << 16 };
+
+/// Most checkpoints a `History` keeps, besides pinned ones, before it drops
+/// every other one.
+const MAX_CHECKPOINTS: usize = 64;
+
+/// Why a `Machine` stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The program ran to its end
+    Halted,
+    /// The next instruction is `,` and no input is buffered; `feed` some or
+    /// `close_input`
+    NeedsInput,
+    /// The steps given to `run_for` are used up
+    OutOfFuel,
+    /// The next instruction has a breakpoint
+    Breakpoint,
+    /// Running backwards, the machine is back where `enable_history` was
+    /// called
+    Start,
+    /// Running backwards, the next instruction is the last to have changed
+    /// the watched cell
+    Watchpoint,
+}
+
+/// A program being run, which can be paused, inspected and resumed.
+///
+/// Input is fed to the machine and output taken from it, so that it can be
+/// driven from an event loop. `Program::run_with` drives one from a reader
+/// and a writer. The cell type is `C`, whatever `Config::cell_width` says.
+///
+/// The limits of the `Config` hold for the machine as a whole, apart from
+/// `timeout`, which holds for each call that runs it. Running into one is an
+/// error that leaves the machine at the instruction that would have run next.
+#[derive(Debug, Clone)]
+pub struct Machine<C = u8> {
+    program: Program,
+    /// See `fingerprint`
+    fingerprint: u64,
+    tape: Tape<C>,
+    pc: usize,
+    config: Config,
+    input: VecDeque<u8>,
+    input_closed: bool,
+    output: Vec<u8>,
+    /// What `Instr::Debug` wrote
+    debug_output: String,
+    steps: u64,
+    input_position: u64,
+    output_position: u64,
+    breakpoints: BTreeSet<usize>,
+    /// Executions of each instruction, if profiling
+    counts: Option<Vec<u64>>,
+    tracer: Option<Tracer<C>>,
+    history: Option<History<C>>,
+}
+
+/// What running backwards takes, see `Machine::enable_history`.
+///
+/// Undoing the instructions one by one back to the last checkpoint is
+/// quick; going further back restores the checkpoint before and runs
+/// forward again to rebuild the undo log. Checkpoints are thinned out as
+/// they pile up, or as their cells outgrow `Config::memory_limit`, so the
+/// further back, the further apart they are.
+#[derive(Debug, Clone)]
+struct History<C> {
+    /// Every `CHECKPOINT_INTERVAL` instructions at first, and after each
+    /// `set_cell`
+    checkpoints: Vec<Checkpoint<C>>,
+    /// How to undo each instruction run since the last checkpoint
+    undo: Vec<Undo>,
+    /// The cells of the entries of `undo`, one after the other
+    cells: Vec<(isize, C)>,
+    /// Instructions run since `enable_history`
+    ticks: u64,
+    /// Input read since `enable_history`
+    input: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+struct Checkpoint<C> {
+    /// Instructions run before it
+    ticks: u64,
+    /// Input read before it
+    input_len: usize,
+    snapshot: Snapshot<C>,
+    /// Kept however many there are: the first, and those taken after an
+    /// edit, which running forward again would not redo
+    pinned: bool,
+}
+
+/// The state an instruction may change, from before it ran.
+#[derive(Debug, Clone)]
+struct Undo {
+    pc: usize,
+    steps: u64,
+    pointer: isize,
+    /// Number of cells the instruction may write, which end
+    /// `History::cells`, by offset from `pointer`
+    cells: usize,
+    /// The byte `,` read
+    input: Option<u8>,
+    output_position: u64,
+}
+
+/// The events recorded since tracing started, see `Machine::enable_tracing`.
+#[derive(Debug, Clone)]
+struct Tracer<C> {
+    options: TraceOptions,
+    /// Recorded but not taken yet
+    events: Vec<TraceEvent<C>>,
+    recorded: u64,
+}
+
+/// The state of a `Machine` at some point, to resume it from later.
+///
+/// Input fed to the machine but not read yet is not part of it: feed the
+/// input again from `input_position`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot<C = u8> {
+    /// Hash of the instructions, to catch resuming with a different
+    /// program or optimization level
+    program: u64,
+    /// To catch resuming with cells or a tape of another kind, which may
+    /// well hold the same values
+    cell_width: CellWidth,
+    tape: TapePolicy,
+    pc: usize,
+    steps: u64,
+    cells: Vec<C>,
+    origin: usize,
+    pointer: isize,
+    input_position: u64,
+    output_position: u64,
+}
+
+impl<C> Snapshot<C> {
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Number of input bytes read so far.
+    pub fn input_position(&self) -> u64 {
+        self.input_position
+    }
+
+    /// Number of output bytes written so far.
+    pub fn output_position(&self) -> u64 {
+        self.output_position
+    }
+}
+
+/// A hash of the instructions of `program` that stays the same across
+/// builds and platforms, unlike `DefaultHasher`, as snapshots outlive them:
+/// 64-bit FNV-1a of a tag for each instruction and its fields, little-endian.
+fn fingerprint(program: &Program) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |value: i64| {
+        for byte in value.to_le_bytes() {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    };
+    for instr in &program.instrs {
+        let (tag, a, b) = match *instr {
+            Instr::Add(n, offset) => (0, n, offset as i64),
+            Instr::Ptr(n) => (1, n as i64, 0),
+            Instr::LoopBegin(target) => (2, target as i64, 0),
+            Instr::LoopEnd(target) => (3, target as i64, 0),
+            Instr::Set(n, offset) => (4, n, offset as i64),
+            Instr::MulAdd(ref targets) => (5, targets.len() as i64, 0),
+            Instr::Scan(stride) => (6, stride as i64, 0),
+            Instr::Out(offset) => (7, offset as i64, 0),
+            Instr::In(offset) => (8, offset as i64, 0),
+            Instr::Debug => (9, 0, 0),
+        };
+        feed(tag);
+        feed(a);
+        feed(b);
+        if let Instr::MulAdd(targets) = instr {
+            for &(offset, factor) in targets {
+                feed(offset as i64);
+                feed(factor);
+            }
+        }
+    }
+    hash
+}
+
+impl<C: Cell> Machine<C> {
+    pub fn new(program: Program, config: Config) -> Result<Self, BfError> {
+        if let TapePolicy::Circular(0) | TapePolicy::Fixed(0) = config.tape {
+            return Err(BfError::InvalidConfig("tape size must not be 0"));
+        }
+        if config
+            .debug_window
+            .is_some_and(|window| window > MAX_DEBUG_WINDOW)
+        {
+            return Err(BfError::InvalidConfig("debug window too wide"));
+        }
+        let tape = Tape::new(config.tape, config.memory_limit).map_err(|e| BfError::Runtime {
+            kind: RuntimeErrorKind::Tape(e),
+            pc: 0,
+            pointer: 0,
+            cell: None,
+            span: None,
+        })?;
+        Ok(Self {
+            fingerprint: fingerprint(&program),
+            program,
+            tape,
+            pc: 0,
+            config,
+            input: VecDeque::new(),
+            input_closed: false,
+            output: Vec::new(),
+            debug_output: String::new(),
+            steps: 0,
+            input_position: 0,
+            output_position: 0,
+            breakpoints: BTreeSet::new(),
+            counts: None,
+            tracer: None,
+            history: None,
+        })
+    }
+
+    /// A machine running `program` from where `snapshot` was taken.
+    pub fn restore(
+        program: Program,
+        config: Config,
+        snapshot: Snapshot<C>,
+    ) -> Result<Self, BfError> {
+        if snapshot.program != fingerprint(&program) || snapshot.pc > program.instrs.len() {
+            return Err(BfError::InvalidSnapshot(
+                "snapshot is of a different program",
+            ));
+        }
+        if snapshot.cell_width != C::WIDTH {
+            return Err(BfError::InvalidSnapshot(
+                "snapshot has cells of a different width",
+            ));
+        }
+        if snapshot.tape != config.tape {
+            return Err(BfError::InvalidSnapshot(
+                "snapshot has a different tape policy",
+            ));
+        }
+        let mut machine = Self::new(program, config)?;
+        machine.load(snapshot)?;
+        Ok(machine)
+    }
+
+    /// Puts the machine in the state of `snapshot`, which is of its program.
+    fn load(&mut self, snapshot: Snapshot<C>) -> Result<(), BfError> {
+        self.tape = Tape::restore(
+            self.config.tape,
+            self.config.memory_limit,
+            snapshot.cells,
+            snapshot.origin,
+            snapshot.pointer,
+        )
+        .map_err(BfError::InvalidSnapshot)?;
+        self.pc = snapshot.pc;
+        self.steps = snapshot.steps;
+        self.input_position = snapshot.input_position;
+        self.output_position = snapshot.output_position;
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> Snapshot<C> {
+        let (cells, origin) = self.tape.cells();
+        Snapshot {
+            program: self.fingerprint,
+            cell_width: C::WIDTH,
+            tape: self.config.tape,
+            pc: self.pc,
+            steps: self.steps,
+            cells: cells.to_vec(),
+            origin,
+            pointer: self.tape.position(),
+            input_position: self.input_position,
+            output_position: self.output_position,
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Index of the instruction to run next.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Position of the pointer; the first cell is at 0.
+    pub fn pointer(&self) -> isize {
+        self.tape.position()
+    }
+
+    /// The cell at `position`.
+    pub fn cell(&self, position: isize) -> C {
+        self.tape.get(position).unwrap_or_default()
+    }
+
+    /// Positions of the cells up to `radius` away from the pointer, as far as
+    /// the tape can have them.
+    pub fn window(&self, radius: isize) -> RangeInclusive<isize> {
+        let pointer = self.pointer();
+        let (first, last) = (
+            pointer.saturating_sub(radius),
+            pointer.saturating_add(radius),
+        );
+        match self.config.tape {
+            TapePolicy::Infinite => first..=last,
+            TapePolicy::Growable => first.max(0)..=last,
+            TapePolicy::Circular(size) | TapePolicy::Fixed(size) => {
+                first.max(0)..=last.min(size as isize - 1)
+            }
+        }
+    }
+
+    /// Sets the cell at `position`, growing the tape if need be. Running
+    /// backwards to before the edit undoes it.
+    pub fn set_cell(&mut self, position: isize, value: C) -> Result<(), BfError> {
+        let Some(offset) = position.checked_sub(self.pointer()) else {
+            return Err(self.fault(Fault::reaching(TapeError::Overflow, position)));
+        };
+        match self.tape.cell(offset) {
+            Ok(cell) => *cell = value,
+            Err(e) => return Err(self.fault(Fault::reaching(e, position))),
+        }
+        if self.history.is_some() {
+            self.checkpoint(true);
+        }
+        Ok(())
+    }
+
+    /// Steps taken so far, counted like `Config::max_steps`.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Makes the machine stop before running instruction `pc`.
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    /// Starts counting how often each instruction runs, see
+    /// `instruction_counts`.
+    pub fn enable_profiling(&mut self) {
+        let len = self.program.instrs.len();
+        self.counts.get_or_insert_with(|| vec![0; len]);
+    }
+
+    /// Executions of each instruction since `enable_profiling`, indexed
+    /// like the instructions of the program.
+    pub fn instruction_counts(&self) -> Option<&[u64]> {
+        self.counts.as_deref()
+    }
+
+    /// Starts recording the instructions that run, see `take_trace`.
+    pub fn enable_tracing(&mut self, options: TraceOptions) {
+        self.tracer = Some(Tracer {
+            options,
+            events: Vec::new(),
+            recorded: 0,
+        });
+    }
+
+    /// Removes and returns the events recorded so far.
+    pub fn take_trace(&mut self) -> Vec<TraceEvent<C>> {
+        self.tracer
+            .as_mut()
+            .map_or_else(Vec::new, |tracer| std::mem::take(&mut tracer.events))
+    }
+
+    /// Number of events recorded since `enable_tracing`, taken or not.
+    pub fn traced_events(&self) -> Option<u64> {
+        self.tracer.as_ref().map(|tracer| tracer.recorded)
+    }
+
+    /// Starts recording what it takes to run backwards, see `back_for`.
+    pub fn enable_history(&mut self) {
+        if self.history.is_none() {
+            self.history = Some(History {
+                checkpoints: vec![Checkpoint {
+                    ticks: 0,
+                    input_len: 0,
+                    snapshot: self.snapshot(),
+                    pinned: true,
+                }],
+                undo: Vec::new(),
+                cells: Vec::new(),
+                ticks: 0,
+                input: Vec::new(),
+            });
+        }
+    }
+
+    /// Appends `bytes` to the input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    /// Marks the end of the input: once the buffered input is used up, `,`
+    /// acts as `Config::eof` says instead of waiting for more.
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Removes and returns the output written so far.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Removes and returns the tape dumps of `Instr::Debug` so far.
+    pub fn take_debug_output(&mut self) -> String {
+        std::mem::take(&mut self.debug_output)
+    }
+
+    /// Runs to the end of the program, reading from `input` whenever it
+    /// needs input. Does not stop at breakpoints. Tape dumps go to stderr.
+    pub fn run(&mut self, input: &mut impl Read, output: &mut impl Write) -> Result<(), BfError> {
+        self.drive(input, output, &mut io::stderr(), &mut io::sink())
+    }
+
+    /// Like `run`, but writes tape dumps to `debug`.
+    pub fn run_with_debug(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        debug: &mut impl Write,
+    ) -> Result<(), BfError> {
+        self.drive(input, output, debug, &mut io::sink())
+    }
+
+    /// Like `run`, but writes the events recorded since `enable_tracing` to
+    /// `trace`, as JSON lines.
+    pub fn run_traced(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        trace: &mut impl Write,
+    ) -> Result<(), BfError> {
+        self.drive(input, output, &mut io::stderr(), trace)
+    }
+
+    fn drive(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+        debug: &mut impl Write,
+        trace: &mut impl Write,
+    ) -> Result<(), BfError> {
+        let mut buf = [0; 1 << 12];
+        // The timeout holds for the whole run, not for each chunk
+        let deadline = self.deadline();
+        loop {
+            // Runs in chunks so that output shows up while the program runs
+            let status = match self.hooked() {
+                true => self.resume(Some(RUN_CHUNK), deadline),
+                false => self.resume_fast(Some(RUN_CHUNK), deadline),
+            };
+            output.write_all(&self.take_output())?;
+            debug.write_all(self.take_debug_output().as_bytes())?;
+            trace::write(trace, &self.take_trace())?;
+            match status? {
+                Status::Halted => {
+                    trace.flush()?;
+                    return Ok(output.flush()?);
+                }
+                Status::NeedsInput => match input.read(&mut buf) {
+                    Ok(0) => self.close_input(),
+                    Ok(n) => self.feed(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e.into()),
+                },
+                Status::OutOfFuel | Status::Breakpoint | Status::Start | Status::Watchpoint => (),
+            }
+        }
+    }
+
+    /// Runs a single instruction; `OutOfFuel` means there are more.
+    pub fn step(&mut self) -> Result<Status, BfError> {
+        self.run_for(1)
+    }
+
+    /// Runs instructions until they have taken at least `steps` steps, or
+    /// until the machine stops for another reason. An instruction such as
+    /// `Scan` can take several steps, so the last one may go past `steps`.
+    pub fn run_for(&mut self, steps: u64) -> Result<Status, BfError> {
+        self.resume(Some(steps), self.deadline())
+    }
+
+    /// Runs until the program ends, needs input or hits a breakpoint.
+    pub fn run_until_input(&mut self) -> Result<Status, BfError> {
+        match self.hooked() {
+            true => self.resume(None, self.deadline()),
+            false => self.resume_fast(None, self.deadline()),
+        }
+    }
+
+    /// Whether anything needs a look at each instruction as it runs: a
+    /// breakpoint, the step limit, profiling, tracing or the history.
+    fn hooked(&self) -> bool {
+        !self.breakpoints.is_empty()
+            || self.config.max_steps.is_some()
+            || self.counts.is_some()
+            || self.tracer.is_some()
+            || self.history.is_some()
+    }
+
+    /// When a run starting now times out.
+    fn deadline(&self) -> Option<Instant> {
+        self.config.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Undoes the last instruction; `OutOfFuel` means there are more.
+    pub fn step_back(&mut self) -> Result<Status, BfError> {
+        self.back_for(1)
+    }
+
+    /// Runs backwards for at least `steps` steps, as far back as
+    /// `enable_history`. Stops at breakpoints, like running forwards.
+    ///
+    /// Input read again is read back, but output stays written.
+    pub fn back_for(&mut self, steps: u64) -> Result<Status, BfError> {
+        self.rewind(Some(steps), None)
+    }
+
+    /// Runs backwards until a breakpoint, or until the next instruction is
+    /// the last to have changed the cell at `watch` if given.
+    pub fn back_until(&mut self, watch: Option<isize>) -> Result<Status, BfError> {
+        self.rewind(None, watch)
+    }
+
+    fn rewind(&mut self, mut fuel: Option<u64>, watch: Option<isize>) -> Result<Status, BfError> {
+        if self.history.is_none() {
+            return Ok(Status::Start);
+        }
+        loop {
+            if fuel == Some(0) {
+                return Ok(Status::OutOfFuel);
+            }
+            let (steps, cell) = (self.steps, watch.map(|position| self.cell(position)));
+            if !self.undo()? {
+                return Ok(Status::Start);
+            }
+            fuel = fuel.map(|fuel| fuel.saturating_sub(steps - self.steps));
+            if let (Some(position), Some(cell)) = (watch, cell) {
+                if self.cell(position) != cell {
+                    return Ok(Status::Watchpoint);
+                }
+            }
+            if self.breakpoints.contains(&self.pc) {
+                return Ok(Status::Breakpoint);
+            }
+        }
+    }
+
+    /// Undoes the last instruction run, unless the history starts there.
+    fn undo(&mut self) -> Result<bool, BfError> {
+        let history = self.history.as_mut().unwrap();
+        if history.undo.is_empty() {
+            let ticks = history.ticks;
+            if ticks == 0 {
+                return Ok(false);
+            }
+            if history.checkpoints.last().unwrap().ticks == ticks {
+                history.checkpoints.pop();
+            }
+            let checkpoint = history.checkpoints.last().unwrap();
+            let (start, input_len) = (checkpoint.ticks, checkpoint.input_len);
+            let snapshot = checkpoint.snapshot.clone();
+            for byte in history.input.drain(input_len..).rev() {
+                self.input.push_front(byte);
+            }
+            history.ticks = start;
+            self.load(snapshot)?;
+            // Output was written the first time round
+            let (output_len, debug_len) = (self.output.len(), self.debug_output.len());
+            for _ in start..ticks {
+                self.tick()?;
+            }
+            self.output.truncate(output_len);
+            self.debug_output.truncate(debug_len);
+        }
+        let undo = self.pop_undo()?;
+        let history = self.history.as_mut().unwrap();
+        history.ticks -= 1;
+        if let Some(byte) = undo.input {
+            history.input.pop();
+            self.input.push_front(byte);
+            self.input_position -= 1;
+        }
+        self.pc = undo.pc;
+        self.steps = undo.steps;
+        self.output_position = undo.output_position;
+        Ok(true)
+    }
+
+    /// A breakpoint on the instruction the machine starts at does not stop
+    /// it, so that it can be resumed from there.
+    fn resume(
+        &mut self,
+        mut fuel: Option<u64>,
+        deadline: Option<Instant>,
+    ) -> Result<Status, BfError> {
+        let mut ticks: u32 = 0;
+        loop {
+            let Some(instr) = self.program.instrs.get(self.pc) else {
+                return Ok(Status::Halted);
+            };
+            if ticks > 0 && self.breakpoints.contains(&self.pc) {
+                return Ok(Status::Breakpoint);
+            }
+            if fuel == Some(0) {
+                return Ok(Status::OutOfFuel);
+            }
+            if let Instr::In(_) = instr {
+                if self.input.is_empty() && !self.input_closed {
+                    return Ok(Status::NeedsInput);
+                }
+            }
+            if self.config.max_steps.is_some_and(|max| self.steps >= max) {
+                return Err(self.error(RuntimeErrorKind::OutOfFuel));
+            }
+            if ticks.is_multiple_of(INTERRUPT_INTERVAL) {
+                if self
+                    .config
+                    .cancel
+                    .as_ref()
+                    .is_some_and(CancelToken::is_cancelled)
+                {
+                    return Err(self.error(RuntimeErrorKind::Cancelled));
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(self.error(RuntimeErrorKind::TimedOut));
+                }
+            }
+            ticks = ticks.wrapping_add(1);
+            if let Some(counts) = &mut self.counts {
+                counts[self.pc] += 1;
+            }
+            let (pc, steps, output_len) = (self.pc, self.steps, self.output.len());
+            self.tick()?;
+            if self.tracer.is_some() {
+                self.record(pc, steps, output_len);
+            }
+            fuel = fuel.map(|fuel| fuel.saturating_sub(self.steps - steps));
+        }
+    }
+
+    /// `resume` for a machine that is not `hooked`. It only checks `fuel`
+    /// along with the timeout, so it may run past it.
+    fn resume_fast(
+        &mut self,
+        fuel: Option<u64>,
+        deadline: Option<Instant>,
+    ) -> Result<Status, BfError> {
+        let start = self.steps;
+        let mut ticks: u32 = 0;
+        loop {
+            let Some(instr) = self.program.instrs.get(self.pc) else {
+                return Ok(Status::Halted);
+            };
+            if let Instr::In(_) = instr {
+                if self.input.is_empty() && !self.input_closed {
+                    return Ok(Status::NeedsInput);
+                }
+            }
+            if ticks.is_multiple_of(INTERRUPT_INTERVAL) {
+                if fuel.is_some_and(|fuel| self.steps - start >= fuel) {
+                    return Ok(Status::OutOfFuel);
+                }
+                if self
+                    .config
+                    .cancel
+                    .as_ref()
+                    .is_some_and(CancelToken::is_cancelled)
+                {
+                    return Err(self.error(RuntimeErrorKind::Cancelled));
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(self.error(RuntimeErrorKind::TimedOut));
+                }
+            }
+            ticks = ticks.wrapping_add(1);
+            self.steps += self.execute().map_err(|fault| self.fault(fault))?;
+        }
+    }
+
+    /// Runs the next instruction, keeping the history if there is one.
+    fn tick(&mut self) -> Result<(), BfError> {
+        if self.history.is_some() {
+            self.push_undo();
+        }
+        match self.execute() {
+            Ok(cost) => {
+                self.steps += cost;
+                if let Some(history) = &mut self.history {
+                    history.input.extend(history.undo.last().unwrap().input);
+                    history.ticks += 1;
+                }
+                Ok(())
+            }
+            Err(fault) => {
+                let error = self.fault(fault);
+                // A `MulAdd` may have written some of its cells before it
+                // failed; the rest of the state is as it was
+                if self.history.is_some() {
+                    self.pop_undo()?;
+                }
+                Err(error)
+            }
+        }
+    }
+
+    /// Snapshots the machine, from where the undo log starts over.
+    fn checkpoint(&mut self, mut pinned: bool) {
+        let snapshot = self.snapshot();
+        let history = self.history.as_mut().unwrap();
+        history.undo.clear();
+        history.cells.clear();
+        if history.checkpoints.last().unwrap().ticks == history.ticks {
+            pinned |= history.checkpoints.pop().unwrap().pinned;
+        }
+        history.checkpoints.push(Checkpoint {
+            ticks: history.ticks,
+            input_len: history.input.len(),
+            snapshot,
+            pinned,
+        });
+        let limit = self.config.memory_limit.unwrap_or(usize::MAX);
+        loop {
+            let checkpoints = &history.checkpoints;
+            let unpinned = checkpoints.iter().filter(|c| !c.pinned).count();
+            let bytes: usize = checkpoints
+                .iter()
+                .map(|c| c.snapshot.cells.len() * size_of::<C>())
+                .sum();
+            if unpinned <= MAX_CHECKPOINTS && bytes <= limit {
+                break;
+            }
+            // Drops every other unpinned one, but never the last, where the
+            // undo log starts
+            let len = checkpoints.len();
+            if unpinned == usize::from(!checkpoints[len - 1].pinned) {
+                break;
+            }
+            let (mut i, mut unpinned) = (0, 0);
+            history.checkpoints.retain(|c| {
+                i += 1;
+                if c.pinned || i == len {
+                    return true;
+                }
+                unpinned += 1;
+                unpinned % 2 == 0
+            });
+        }
+    }
+
+    /// Records how to undo the next instruction. Takes a checkpoint first
+    /// when one is due.
+    fn push_undo(&mut self) {
+        let history = self.history.as_ref().unwrap();
+        if history.ticks - history.checkpoints.last().unwrap().ticks >= CHECKPOINT_INTERVAL {
+            self.checkpoint(false);
+        }
+        let history = self.history.as_mut().unwrap();
+        let tape = &self.tape;
+        let len = history.cells.len();
+        let instr = &self.program.instrs[self.pc];
+        match *instr {
+            Instr::Add(_, offset) | Instr::Set(_, offset) | Instr::In(offset) => {
+                history.cells.push((offset, tape.peek(offset)));
+            }
+            Instr::MulAdd(ref factors) => history.cells.extend(
+                factors
+                    .iter()
+                    .map(|&(offset, _)| offset)
+                    .chain([0])
+                    .map(|offset| (offset, tape.peek(offset))),
+            ),
+            _ => (),
+        }
+        history.undo.push(Undo {
+            pc: self.pc,
+            steps: self.steps,
+            pointer: tape.position(),
+            cells: history.cells.len() - len,
+            input: match instr {
+                Instr::In(_) => self.input.front().copied(),
+                _ => None,
+            },
+            output_position: self.output_position,
+        });
+    }
+
+    /// Removes the last entry of the undo log, putting back the pointer and
+    /// the cells it recorded.
+    fn pop_undo(&mut self) -> Result<Undo, BfError> {
+        let history = self.history.as_mut().unwrap();
+        let undo = history.undo.pop().unwrap();
+        let cells = history.cells.len() - undo.cells..history.cells.len();
+        let shift = undo.pointer - self.tape.position();
+        self.tape.shift(shift).map_err(|e| self.error(e.into()))?;
+        for i in cells.clone().rev() {
+            let (offset, value) = self.history.as_ref().unwrap().cells[i];
+            match self.tape.cell(offset) {
+                Ok(cell) => *cell = value,
+                Err(e) => return Err(self.error(e.into())),
+            }
+        }
+        self.history.as_mut().unwrap().cells.truncate(cells.start);
+        Ok(undo)
+    }
+
+    /// Runs the instruction at `pc` and moves on to the next. Returns the
+    /// number of steps taken. Inlined, as the run loops spend most of their
+    /// time here.
+    #[inline(always)]
+    fn execute(&mut self) -> Result<u64, Fault> {
+        let tape = &mut self.tape;
+        let pointer = tape.position();
+        let reach = |offset| move |e| Fault::reaching(e, pointer.saturating_add(offset));
+        let mut cost = 1;
+        match self.program.instrs[self.pc] {
+            Instr::Add(x, offset) => {
+                let cell = tape.cell(offset).map_err(reach(offset))?;
+                *cell = cell.add(x).ok_or(RuntimeErrorKind::CellOverflow)?;
+            }
+            Instr::Ptr(x) => tape.shift(x).map_err(reach(x))?,
+            Instr::LoopBegin(x) => {
+                if tape.current().is_zero() {
+                    self.pc = x;
+                }
+            }
+            Instr::LoopEnd(x) => {
+                if !tape.current().is_zero() {
+                    self.pc = x;
+                }
+            }
+            Instr::Set(x, offset) => {
+                *tape.cell(offset).map_err(reach(offset))? =
+                    C::from_i64(x).ok_or(RuntimeErrorKind::CellOverflow)?;
+            }
+            Instr::MulAdd(ref factors) => {
+                let count = tape.current();
+                if !count.is_zero() {
+                    for &(offset, factor) in factors {
+                        let cell = tape.cell(offset).map_err(reach(offset))?;
+                        *cell = cell
+                            .mul_add(count, factor)
+                            .ok_or(RuntimeErrorKind::CellOverflow)?;
+                    }
+                    tape.set_current(C::default());
+                    cost += factors.len() as u64;
+                }
+            }
+            Instr::Scan(x) => {
+                // A failed scan leaves the pointer at the last cell it reached
+                cost += tape
+                    .scan(x)
+                    .map_err(|e| Fault::reaching(e, tape.position().saturating_add(x)))?
+                    as u64;
+                if !tape.current().is_zero() {
+                    // Went round a circular tape without finding a zero:
+                    // scan again, once the limits have been checked
+                    return Ok(cost);
+                }
+            }
+            Instr::Out(offset) => {
+                let cell = *tape.cell(offset).map_err(reach(offset))?;
+                match self.config.output {
+                    OutputMode::Bytes => {
+                        self.output.push(cell.to_byte());
+                        self.output_position += 1;
+                    }
+                    OutputMode::Unicode => {
+                        let c = cell.to_char().ok_or(RuntimeErrorKind::NotUnicode)?;
+                        let mut buf = [0; 4];
+                        self.output
+                            .extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        self.output_position += c.len_utf8() as u64;
+                    }
+                }
+            }
+            Instr::In(offset) => {
+                let cell = tape.cell(offset).map_err(reach(offset))?;
+                match self.input.pop_front() {
+                    Some(v) => {
+                        *cell = C::from_byte(v);
+                        self.input_position += 1;
+                    }
+                    None => match self.config.eof {
+                        EofBehavior::Zero => *cell = C::default(),
+                        EofBehavior::MinusOne => *cell = C::from_i64(-1).unwrap(),
+                        EofBehavior::Unchanged => (),
+                    },
+                }
+            }
+            Instr::Debug => self.dump(),
+        }
+        self.pc += 1;
+        Ok(cost)
+    }
+
+    /// Records instruction `pc`, which just ran from `steps` steps and wrote
+    /// `output[output_len..]`, if the tracer wants it.
+    fn record(&mut self, pc: usize, steps: u64, output_len: usize) {
+        let tracer = self.tracer.as_mut().unwrap();
+        let span = &self.program.spans[pc];
+        if !tracer.options.covers(span)
+            || tracer
+                .options
+                .limit
+                .is_some_and(|limit| tracer.recorded >= limit)
+        {
+            return;
+        }
+        tracer.events.push(TraceEvent {
+            step: steps,
+            pc,
+            instr: self.program.instrs[pc].clone(),
+            span: span.clone(),
+            pointer: self.tape.position(),
+            cell: self.tape.current(),
+            output: self.output.get(output_len..).unwrap_or_default().to_vec(),
+        });
+        tracer.recorded += 1;
+    }
+
+    /// Writes the pointer, pc and the cells around the pointer to
+    /// `debug_output`, the cell under the pointer in brackets.
+    fn dump(&mut self) {
+        let radius = self.config.debug_window.unwrap_or(DEBUG_WINDOW) as isize;
+        let window = self.window(radius);
+        let pointer = self.pointer();
+        let out = &mut self.debug_output;
+        write!(
+            out,
+            "# instruction {}, pointer at {pointer}, cells {window:?}:",
+            self.pc
+        )
+        .unwrap();
+        for position in window {
+            let cell = self.tape.get(position).unwrap_or_default();
+            match position == pointer {
+                true => write!(out, " [{cell:?}]"),
+                false => write!(out, " {cell:?}"),
+            }
+            .unwrap();
+        }
+        out.push('\n');
+    }
+
+    fn error(&self, kind: RuntimeErrorKind) -> BfError {
+        self.fault(kind.into())
+    }
+
+    fn fault(&self, fault: Fault) -> BfError {
+        BfError::Runtime {
+            kind: fault.kind,
+            pc: self.pc,
+            pointer: self.tape.position(),
+            cell: fault.cell,
+            span: self.program.span(self.pc),
+        }
+    }
+}
+
+/// Why an instruction failed, and the cell it reached for if the tape
+/// refused it.
+struct Fault {
+    kind: RuntimeErrorKind,
+    cell: Option<isize>,
+}
+
+impl Fault {
+    fn reaching(e: TapeError, cell: isize) -> Self {
+        Fault {
+            kind: e.into(),
+            cell: Some(cell),
+        }
+    }
+}
+
+impl From<RuntimeErrorKind> for Fault {
+    fn from(kind: RuntimeErrorKind) -> Self {
+        Fault { kind, cell: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ParseOptions;
+
+    fn parse(source: &str) -> Machine {
+        Machine::new(
+            Program::parse_raw(source, ParseOptions::default()).unwrap(),
+            Config::default(),
+        )
+        .unwrap()
+    }
+    #[test]
+    fn step() {
+        let mut machine = parse("+>++<[->+<]");
+        assert_eq!(machine.step().unwrap(), Status::OutOfFuel);
+        assert_eq!((machine.pc(), machine.cell(0)), (1, 1));
+        assert_eq!(machine.run_for(3).unwrap(), Status::OutOfFuel);
+        assert_eq!(
+            (machine.pc(), machine.pointer(), machine.cell(1)),
+            (4, 1, 2)
+        );
+        assert_eq!(machine.run_for(100).unwrap(), Status::Halted);
+        assert_eq!((machine.cell(0), machine.cell(1)), (0, 3));
+        assert_eq!(machine.steps(), 4 + 2 + 5);
+        assert_eq!(machine.step().unwrap(), Status::Halted);
+    }
+    #[test]
+    fn input() {
+        let mut machine = parse("+.,.,.,.");
+        assert_eq!(machine.run_until_input().unwrap(), Status::NeedsInput);
+        assert_eq!(machine.take_output(), [1]);
+        machine.feed(b"ab");
+        assert_eq!(machine.run_until_input().unwrap(), Status::NeedsInput);
+        assert_eq!(machine.take_output(), b"ab");
+        machine.close_input();
+        assert_eq!(machine.run_until_input().unwrap(), Status::Halted);
+        assert_eq!(machine.take_output(), [0]);
+    }
+    #[test]
+    fn breakpoints() {
+        let program = Program::parse("++[>+<-]>.").unwrap();
+        let mut machine = Machine::<u16>::new(program, Config::default()).unwrap();
+        machine.add_breakpoint(3);
+        assert_eq!(machine.run_until_input().unwrap(), Status::Breakpoint);
+        assert_eq!((machine.pc(), machine.cell(1)), (3, 0));
+        assert_eq!(machine.run_until_input().unwrap(), Status::Breakpoint);
+        assert_eq!((machine.pc(), machine.cell(1)), (3, 1));
+        assert!(machine.remove_breakpoint(3));
+        assert_eq!(machine.run_until_input().unwrap(), Status::Halted);
+        assert_eq!(machine.take_output(), [2]);
+    }
+    #[test]
+    fn snapshots() {
+        let program = Program::parse_raw(",[.,]<", ParseOptions::default()).unwrap();
+        let config = Config {
+            tape: crate::TapePolicy::Infinite,
+            ..Config::default()
+        };
+        let mut machine = Machine::<u32>::new(program.clone(), config.clone()).unwrap();
+        machine.feed(b"abc");
+        assert_eq!(machine.run_for(6).unwrap(), Status::OutOfFuel);
+        assert_eq!(machine.take_output(), b"ab");
+        let snapshot = machine.snapshot();
+        assert_eq!(
+            (snapshot.input_position(), snapshot.output_position()),
+            (2, 2)
+        );
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: Snapshot<u32> = serde_json::from_str(&json).unwrap();
+        let mut resumed = Machine::restore(program, config.clone(), snapshot).unwrap();
+        resumed.feed(b"c");
+        resumed.close_input();
+        machine.close_input();
+        assert_eq!(resumed.run_until_input().unwrap(), Status::Halted);
+        assert_eq!(machine.run_until_input().unwrap(), Status::Halted);
+        assert_eq!(resumed.take_output(), machine.take_output());
+        assert_eq!(resumed.snapshot(), machine.snapshot());
+        assert_eq!(resumed.pointer(), -1);
+
+        let snapshot = machine.snapshot();
+        let other = Program::parse_raw("+", ParseOptions::default()).unwrap();
+        assert!(Machine::restore(other, Config::default(), snapshot.clone()).is_err());
+        // Just as long, and the snapshot would fit it
+        let other = Program::parse_raw(",[.,]>", ParseOptions::default()).unwrap();
+        assert!(Machine::restore(other, config.clone(), snapshot.clone()).is_err());
+        let program = machine.program().clone();
+        let ring = Config {
+            tape: crate::TapePolicy::Circular(30000),
+            ..config.clone()
+        };
+        assert!(Machine::restore(program.clone(), ring, snapshot.clone()).is_err());
+        let narrow: Snapshot<u8> = serde_json::from_str(&json).unwrap();
+        assert!(Machine::restore(program.clone(), config.clone(), narrow).is_err());
+        assert!(Machine::restore(program, config, snapshot).is_ok());
+    }
+    #[test]
+    fn tracing() {
+        let mut machine = parse("++\n[>+.<-]");
+        machine.enable_tracing(TraceOptions {
+            range: Some(4..7),
+            limit: Some(3),
+        });
+        assert_eq!(machine.run_until_input().unwrap(), Status::Halted);
+        let trace = machine.take_trace();
+        let events: Vec<_> = trace
+            .iter()
+            .map(|e| (e.step, e.pc, e.pointer, e.cell, &e.output[..]))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (3, 3, 1, 0, &[][..]),
+                (4, 4, 1, 1, &[][..]),
+                (5, 5, 1, 1, &[1][..])
+            ]
+        );
+        assert_eq!((&trace[2].instr, &trace[2].span), (&Instr::Out(0), &(6..7)));
+        assert_eq!(machine.traced_events(), Some(3));
+        assert!(machine.take_trace().is_empty());
+    }
+    #[test]
+    fn history() {
+        let mut machine = parse("+++>+<[-]>,.");
+        machine.enable_history();
+        machine.feed(b"a");
+        assert_eq!(machine.run_until_input().unwrap(), Status::Halted);
+        assert_eq!(machine.back_until(Some(1)).unwrap(), Status::Watchpoint);
+        assert_eq!(
+            (machine.pc(), machine.cell(1), machine.steps()),
+            (10, 1, 14)
+        );
+        assert_eq!(machine.back_until(Some(1)).unwrap(), Status::Watchpoint);
+        assert_eq!((machine.pc(), machine.cell(1)), (4, 0));
+        assert_eq!(machine.back_for(2).unwrap(), Status::OutOfFuel);
+        assert_eq!(
+            (machine.pc(), machine.pointer(), machine.cell(0)),
+            (2, 0, 2)
+        );
+        machine.add_breakpoint(7);
+        assert_eq!(machine.run_until_input().unwrap(), Status::Breakpoint);
+        assert_eq!(machine.run_until_input().unwrap(), Status::Breakpoint);
+        assert_eq!(machine.cell(0), 2);
+        assert_eq!(machine.back_until(None).unwrap(), Status::Breakpoint);
+        assert_eq!((machine.pc(), machine.cell(0)), (7, 3));
+        assert_eq!(machine.step_back().unwrap(), Status::OutOfFuel);
+        assert_eq!(machine.back_until(None).unwrap(), Status::Start);
+        assert_eq!((machine.pc(), machine.steps(), machine.cell(0)), (0, 0, 0));
+        assert!(machine.remove_breakpoint(7));
+        machine.take_output();
+        assert_eq!(machine.run_until_input().unwrap(), Status::Halted);
+        assert_eq!(machine.take_output(), b"a");
+    }
+    #[test]
+    fn checkpoints() {
+        let program = Program::parse_raw("+[+]-", ParseOptions::default()).unwrap();
+        let mut machine = Machine::<u16>::new(program, Config::default()).unwrap();
+        machine.enable_history();
+        machine.run_for(100_000).unwrap();
+        let middle = machine.snapshot();
+        machine.run_until_input().unwrap();
+        let end = machine.snapshot();
+        machine.back_for(machine.steps() - 100_000).unwrap();
+        assert_eq!(machine.snapshot(), middle);
+        machine.run_until_input().unwrap();
+        assert_eq!(machine.snapshot(), end);
+        assert_eq!(machine.back_until(None).unwrap(), Status::Start);
+        assert_eq!((machine.steps(), machine.cell(0)), (0, 0));
+    }
+    #[test]
+    fn edits() {
+        let program = Program::parse_raw("+[+]-", ParseOptions::default()).unwrap();
+        let mut machine = Machine::<u16>::new(program, Config::default()).unwrap();
+        machine.enable_history();
+        machine.run_for(3).unwrap();
+        machine.set_cell(5, 42).unwrap();
+        machine.run_for(70_000).unwrap();
+        machine.back_for(69_999).unwrap();
+        assert_eq!((machine.steps(), machine.cell(5)), (4, 42));
+        assert_eq!(machine.back_for(1).unwrap(), Status::OutOfFuel);
+        assert_eq!(machine.cell(5), 42);
+        // Going back past the edit undoes it
+        assert_eq!(machine.back_for(1).unwrap(), Status::OutOfFuel);
+        assert_eq!(machine.cell(5), 0);
+    }
+    #[test]
+    fn thinning() {
+        let mut machine = parse("+[>+<]");
+        machine.enable_history();
+        machine.run_for(100_000).unwrap();
+        let middle = machine.snapshot();
+        machine.run_for(50_000).unwrap();
+        let checkpoints = &machine.history.as_ref().unwrap().checkpoints;
+        assert!(checkpoints.len() <= MAX_CHECKPOINTS + 1);
+        machine.back_for(50_000).unwrap();
+        assert_eq!(machine.snapshot(), middle);
+
+        // Checkpoints of 100 cells each, in at most 1000 bytes
+        let config = Config {
+            tape: crate::TapePolicy::Fixed(100),
+            memory_limit: Some(1000),
+            ..Config::default()
+        };
+        let program = Program::parse_raw("+[>+<]", ParseOptions::default()).unwrap();
+        let mut machine = Machine::<u8>::new(program, config).unwrap();
+        machine.enable_history();
+        machine.run_for(100_000).unwrap();
+        let middle = machine.snapshot();
+        machine.run_for(50_000).unwrap();
+        let checkpoints = &machine.history.as_ref().unwrap().checkpoints;
+        assert!(checkpoints.len() <= 10);
+        machine.back_for(50_000).unwrap();
+        assert_eq!(machine.snapshot(), middle);
+    }
+    #[test]
+    fn failed_steps() {
+        // The second target overflows once the first has been written
+        let program = Program::parse("[->+>+<<]")
+            .unwrap()
+            .optimize(crate::OptLevel::O3);
+        assert!(matches!(program.instrs[..], [Instr::MulAdd(_)]));
+        let mut machine = Machine::<i64>::new(program, Config::default()).unwrap();
+        machine.enable_history();
+        machine.set_cell(0, 1).unwrap();
+        machine.set_cell(2, i64::MAX).unwrap();
+        assert!(matches!(
+            machine.step(),
+            Err(BfError::Runtime {
+                kind: RuntimeErrorKind::CellOverflow,
+                ..
+            })
+        ));
+        assert_eq!(
+            (machine.cell(0), machine.cell(1), machine.cell(2)),
+            (1, 0, i64::MAX)
+        );
+        assert_eq!(machine.step_back().unwrap(), Status::Start);
+        assert_eq!(machine.cell(1), 0);
+    }
+    #[test]
+    fn errors() {
+        let config = Config {
+            max_steps: Some(5),
+            ..Config::default()
+        };
+        let mut machine = Machine::<u8>::new(
+            Program::parse_raw("+[+]<", ParseOptions::default()).unwrap(),
+            config,
+        )
+        .unwrap();
+        assert!(machine.run_until_input().is_err());
+        assert_eq!((machine.pc(), machine.steps()), (3, 5));
+        let mut machine = parse("<");
+        assert!(machine.step().is_err());
+        assert_eq!((machine.pc(), machine.pointer()), (0, 0));
+    }
+}
@@ -1,21 +1,608 @@
-use clap::Parser;
-use rust_bf::Program;
-use std::fs;
-use std::io::{stdin, stdout};
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_bf::debugger::Debugger;
+use rust_bf::diagnostics;
+use rust_bf::optimizer::{Optimizer, PASS_NAMES};
+use rust_bf::profiler::Profile;
+use rust_bf::trace::{self, TraceEvent, TraceOptions};
+use rust_bf::{
+    with_cell_width, BfError, Cell, CellWidth, Config, EofBehavior, Machine, OptLevel, OutputMode,
+    ParseOptions, Program, RuntimeErrorKind, Snapshot, Span, TapePolicy, MAX_DEBUG_WINDOW,
+};
+use std::fs::{self, File};
+use std::io::{self, stdin, stdout, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+/// Exit status for a runtime error such as pointer underflow
+const EXIT_RUNTIME: i32 = 1;
+/// Exit status for bad arguments, the same as clap uses
+const EXIT_USAGE: i32 = 2;
+/// Exit status for a program with unbalanced brackets
+const EXIT_PARSE: i32 = 3;
+/// Exit status when reading the file, input or output fails
+const EXIT_IO: i32 = 4;
+/// Exit status when the program takes more than --max-steps steps
+const EXIT_OUT_OF_FUEL: i32 = 5;
+/// Exit status when the program runs for longer than --timeout
+const EXIT_TIMEOUT: i32 = 6;
+/// Exit status of trace-diff when the traces diverge
+const EXIT_DIVERGED: i32 = 1;
 
 /// Brainfuck interpreter in Rust
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "Exit status: 0 on success, 1 on a runtime error, 2 on bad arguments, \
+                  3 on a parse error, 4 on an I/O error, 5 when the step limit is exceeded, 6 on a timeout."
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Name of the Brainfuck file to execute
+    #[arg(required = true)]
+    filename: Option<String>,
+
+    #[command(flatten)]
+    opt: OptArgs,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Stop the program after this many steps
+    #[arg(long, value_name = "STEPS")]
+    max_steps: Option<u64>,
+
+    /// Stop the program after this long, in seconds (ms and m suffixes allowed)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Save the state of the program to FILE if it stops because of
+    /// --max-steps or --timeout
+    #[arg(long, value_name = "FILE")]
+    checkpoint: Option<PathBuf>,
+
+    /// Continue from a state saved with --checkpoint, given the same program,
+    /// options and input
+    #[arg(long, value_name = "FILE")]
+    resume: Option<PathBuf>,
+
+    /// Record each instruction that runs, with the pointer and cell it
+    /// leaves, to FILE as JSON lines
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Only trace the instructions from these lines of the source
+    #[arg(long, value_name = "FIRST-LAST", requires = "trace", value_parser = parse_lines)]
+    trace_lines: Option<(usize, usize)>,
+
+    /// Stop tracing after this many instructions
+    #[arg(long, value_name = "EVENTS", requires = "trace")]
+    trace_limit: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Step through a program interactively
+    Debug(DebugArgs),
+    /// Run a program and report where it spends its time, on stderr
+    Profile(ProfileArgs),
+    /// Find where two traces written with --trace first disagree, exiting
+    /// with 1 if they do
+    TraceDiff(TraceDiffArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct DebugArgs {
+    /// Name of the Brainfuck file to debug
     filename: String,
+
+    #[command(flatten)]
+    opt: OptArgs,
+
+    /// Give the contents of FILE to the program as input, instead of asking
+    /// for it
+    #[arg(long, value_name = "FILE")]
+    input: Option<PathBuf>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ProfileArgs {
+    /// Name of the Brainfuck file to profile
+    filename: String,
+
+    #[command(flatten)]
+    opt: OptArgs,
+
+    /// Number of loops and of instructions to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+
+    /// Also print the source with how often each line ran
+    #[arg(long)]
+    annotate: bool,
+
+    /// Write the source, shaded by how often each line ran, to FILE as HTML
+    #[arg(long, value_name = "FILE")]
+    html: Option<PathBuf>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct TraceDiffArgs {
+    /// The first trace
+    a: PathBuf,
+
+    /// The trace to compare it with
+    b: PathBuf,
+
+    /// The Brainfuck file both traces come from, to show where they diverge
+    #[arg(long, value_name = "FILE")]
+    source: Option<String>,
+
+    /// Number of events to show before the divergence
+    #[arg(long, value_name = "EVENTS", default_value_t = 5)]
+    context: usize,
+}
+
+/// Options for how the program is optimized.
+#[derive(clap::Args, Debug)]
+struct OptArgs {
+    /// Optimization level, from 0 (none) to 3 (every pass); 3 by default,
+    /// 0 for debug and profile, so that instructions match the source
+    #[arg(short = 'O', value_name = "LEVEL", value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: Option<u8>,
+
+    /// Turn off an optimization pass of the chosen level (may be repeated)
+    #[arg(long, value_name = "PASS", value_parser = PASS_NAMES)]
+    disable_pass: Vec<String>,
+}
+
+impl OptArgs {
+    /// Runs the passes chosen, of level `default` unless -O gives another.
+    fn optimize(&self, program: Program, default: OptLevel) -> Program {
+        let level = self
+            .opt_level
+            .map_or(default, |level| OptLevel::try_from(level).unwrap());
+        let mut optimizer = Optimizer::new(level);
+        for pass in &self.disable_pass {
+            optimizer.disable(pass);
+        }
+        optimizer.run(program)
+    }
+}
+
+/// Options for how the program runs.
+#[derive(clap::Args, Debug)]
+struct MachineArgs {
+    /// Width of a tape cell
+    #[arg(long, value_enum, default_value_t = CellSize::U8)]
+    cell_size: CellSize,
+
+    /// What `,` stores once the input is exhausted
+    #[arg(long, value_enum, default_value_t = Eof::Zero)]
+    eof: Eof,
+
+    /// What happens at the ends of the tape
+    #[arg(long, value_enum, default_value_t = Tape::Growable)]
+    tape: Tape,
+
+    /// Number of cells of a circular or fixed tape
+    #[arg(long, value_name = "CELLS", default_value_t = 30000)]
+    tape_size: usize,
+
+    /// Most memory the tape may use, in bytes (K, M and G suffixes allowed)
+    #[arg(long, value_name = "BYTES", value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// How `.` writes a cell
+    #[arg(long, value_enum, default_value_t = Output::Bytes)]
+    output: Output,
+
+    /// Treat `#` as an instruction that dumps the tape to stderr
+    #[arg(long)]
+    hash_dumps: bool,
+
+    /// Number of cells on each side of the pointer a `#` dump shows
+    #[arg(long, value_name = "CELLS", default_value_t = 8, value_parser = parse_window)]
+    dump_window: usize,
+}
+
+impl MachineArgs {
+    fn config(&self) -> Config {
+        Config {
+            cell_width: self.cell_size.into(),
+            eof: self.eof.into(),
+            tape: match self.tape {
+                Tape::Growable => TapePolicy::Growable,
+                Tape::Infinite => TapePolicy::Infinite,
+                Tape::Circular => TapePolicy::Circular(self.tape_size),
+                Tape::Fixed => TapePolicy::Fixed(self.tape_size),
+            },
+            memory_limit: self.max_memory,
+            output: match self.output {
+                Output::Bytes => OutputMode::Bytes,
+                Output::Unicode => OutputMode::Unicode,
+            },
+            debug_window: Some(self.dump_window),
+            ..Config::default()
+        }
+    }
+
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            debug: self.hash_dumps,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Output {
+    /// The low byte of the cell, unchanged
+    Bytes,
+    /// The UTF-8 encoding of the code point in the cell
+    Unicode,
+}
+
+/// Parses a byte count such as `4096`, `64K` or `1G`.
+fn parse_size(arg: &str) -> Result<usize, String> {
+    let (digits, shift) = match arg.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 10),
+        None => match arg.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 20),
+            None => match arg.strip_suffix(['G', 'g']) {
+                Some(digits) => (digits, 30),
+                None => (arg, 0),
+            },
+        },
+    };
+    let n: usize = digits.parse().map_err(|e| format!("{e}"))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| "size too large".to_string())
+}
+
+/// Parses a --dump-window no wider than tape dumps may be.
+fn parse_window(arg: &str) -> Result<usize, String> {
+    let cells: usize = arg.parse().map_err(|e| format!("{e}"))?;
+    if cells > MAX_DEBUG_WINDOW {
+        return Err(format!("at most {MAX_DEBUG_WINDOW} cells on each side"));
+    }
+    Ok(cells)
+}
+
+/// Parses a duration such as `10`, `1.5`, `500ms` or `2m`.
+fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let (number, scale) = match arg.strip_suffix("ms") {
+        Some(number) => (number, 0.001),
+        None => match arg.strip_suffix('m') {
+            Some(number) => (number, 60.0),
+            None => (arg.strip_suffix('s').unwrap_or(arg), 1.0),
+        },
+    };
+    let n: f64 = number.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(n * scale).map_err(|e| format!("{e}"))
+}
+
+/// Parses a range of lines such as `10-20`, or `7` for a single line.
+fn parse_lines(arg: &str) -> Result<(usize, usize), String> {
+    let (first, last) = arg.split_once('-').unwrap_or((arg, arg));
+    let first: usize = first.parse().map_err(|e| format!("{e}"))?;
+    let last: usize = last.parse().map_err(|e| format!("{e}"))?;
+    if first == 0 || last < first {
+        return Err("lines start at 1 and must be in order".to_string());
+    }
+    Ok((first, last))
+}
+
+/// Bytes of lines `first` to `last` of `source`.
+fn line_span(source: &str, (first, last): (usize, usize)) -> Span {
+    let mut starts = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .chain(std::iter::repeat(source.len()));
+    let start = starts.nth(first - 1).unwrap();
+    let end = starts.nth(last - first).unwrap();
+    start..end
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum CellSize {
+    /// Wrapping 8-bit cells
+    #[value(name = "8")]
+    U8,
+    /// Wrapping 16-bit cells
+    #[value(name = "16")]
+    U16,
+    /// Wrapping 32-bit cells
+    #[value(name = "32")]
+    U32,
+    /// Wrapping 64-bit cells
+    #[value(name = "64")]
+    U64,
+    /// Signed 64-bit cells, overflow is an error
+    #[value(name = "i64")]
+    I64,
+}
+
+impl From<CellSize> for CellWidth {
+    fn from(size: CellSize) -> Self {
+        match size {
+            CellSize::U8 => CellWidth::U8,
+            CellSize::U16 => CellWidth::U16,
+            CellSize::U32 => CellWidth::U32,
+            CellSize::U64 => CellWidth::U64,
+            CellSize::I64 => CellWidth::I64,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Eof {
+    /// Store 0
+    Zero,
+    /// Store -1 (255 for 8-bit cells)
+    MinusOne,
+    /// Leave the cell unchanged
+    Unchanged,
+}
+
+impl From<Eof> for EofBehavior {
+    fn from(eof: Eof) -> Self {
+        match eof {
+            Eof::Zero => EofBehavior::Zero,
+            Eof::MinusOne => EofBehavior::MinusOne,
+            Eof::Unchanged => EofBehavior::Unchanged,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Tape {
+    /// Grows to the right, moving left of the first cell is an error
+    Growable,
+    /// Grows in both directions
+    Infinite,
+    /// Wraps around after --tape-size cells
+    Circular,
+    /// Has --tape-size cells, leaving them is an error
+    Fixed,
 }
 
 fn main() {
     let args = Args::parse();
-    let source = fs::read_to_string(args.filename).expect("Unable to read file");
-    Program::parse(&source)
-        .unwrap()
-        .run(&mut stdin(), &mut stdout())
-        .unwrap();
+    let (filename, machine) = match &args.command {
+        Some(Command::Debug(debug)) => (&debug.filename, &debug.machine),
+        Some(Command::Profile(profile)) => (&profile.filename, &profile.machine),
+        Some(Command::TraceDiff(diff)) => exit(trace_diff(diff)),
+        None => (args.filename.as_ref().unwrap(), &args.machine),
+    };
+    let source = fs::read_to_string(filename).unwrap_or_else(|e| {
+        eprintln!("error: unable to read {filename}: {e}");
+        exit(EXIT_IO)
+    });
+    let result =
+        Program::parse_raw(&source, machine.parse_options()).and_then(|program| {
+            match &args.command {
+                Some(Command::Debug(debug)) => debug_program(program, debug, &source),
+                Some(Command::Profile(profile)) => profile_program(program, profile, &source),
+                Some(Command::TraceDiff(_)) => unreachable!(),
+                None => run_program(program, &args, &source),
+            }
+        });
+    if let Err(e) = result {
+        eprint!("{}", diagnostics::render(&e, filename, &source));
+        exit(exit_code(&e));
+    }
+}
+
+fn run_program(program: Program, args: &Args, source: &str) -> Result<(), BfError> {
+    let program = args.opt.optimize(program, OptLevel::O3);
+    let config = Config {
+        max_steps: args.max_steps,
+        timeout: args.timeout,
+        ..args.machine.config()
+    };
+    with_cell_width!(config.cell_width, C => run::<C>(program, config, args, source))
+}
+
+fn debug_program(program: Program, args: &DebugArgs, source: &str) -> Result<(), BfError> {
+    let program = args.opt.optimize(program, OptLevel::O0);
+    let config = args.machine.config();
+    with_cell_width!(config.cell_width, C => debug::<C>(program, config, args, source))
+}
+
+fn profile_program(program: Program, args: &ProfileArgs, source: &str) -> Result<(), BfError> {
+    let program = args.opt.optimize(program, OptLevel::O0);
+    let config = args.machine.config();
+    with_cell_width!(config.cell_width, C => profile::<C>(program, config, args, source))
+}
+
+/// Runs `program` on stdin and stdout, then reports its profile, even if it
+/// failed.
+fn profile<C: Cell>(
+    program: Program,
+    config: Config,
+    args: &ProfileArgs,
+    source: &str,
+) -> Result<(), BfError> {
+    let mut machine = Machine::<C>::new(program, config)?;
+    machine.enable_profiling();
+    let start = Instant::now();
+    let result = machine.run(&mut stdin(), &mut stdout());
+    let elapsed = start.elapsed();
+    let profile = Profile::new(machine.program(), machine.instruction_counts().unwrap());
+    eprintln!("Profile of {}:", args.filename);
+    eprint!("{}", profile.report(source, args.top));
+    eprintln!("\nWall time: {elapsed:.2?}");
+    if args.annotate {
+        eprint!("\n{}", profile.annotate(source));
+    }
+    if let Some(path) = &args.html {
+        fs::write(path, profile.html(&args.filename, source))?;
+    }
+    result
+}
+
+/// Runs debugger commands read from stdin until `quit` or the end of stdin.
+fn debug<C: Cell>(
+    program: Program,
+    config: Config,
+    args: &DebugArgs,
+    source: &str,
+) -> Result<(), BfError> {
+    let mut machine = Machine::<C>::new(program, config)?;
+    if let Some(path) = &args.input {
+        machine.feed(&fs::read(path)?);
+        machine.close_input();
+    }
+    let mut debugger = Debugger::new(machine, &args.filename, source);
+    let mut out = stdout().lock();
+    writeln!(
+        out,
+        "Debugging {}, type `help` for the commands",
+        args.filename
+    )?;
+    let mut line = String::new();
+    loop {
+        write!(out, "(bf) ")?;
+        out.flush()?;
+        line.clear();
+        if stdin().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        match debugger.command(&line) {
+            Some(reply) => write!(out, "{reply}")?,
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Runs `program` on stdin and stdout, resuming from, checkpointing to and
+/// tracing to the files given in `args`.
+fn run<C: Cell>(
+    program: Program,
+    mut config: Config,
+    args: &Args,
+    source: &str,
+) -> Result<(), BfError> {
+    let mut machine = match &args.resume {
+        Some(path) => {
+            let snapshot: Snapshot<C> = serde_json::from_reader(BufReader::new(File::open(path)?))
+                .map_err(io::Error::from)?;
+            // Skip the input read before the checkpoint
+            io::copy(
+                &mut stdin().take(snapshot.input_position()),
+                &mut io::sink(),
+            )?;
+            // --max-steps counts the steps of this run only
+            config.max_steps = config
+                .max_steps
+                .map(|max| max.saturating_add(snapshot.steps()));
+            Machine::restore(program, config, snapshot)?
+        }
+        None => Machine::new(program, config)?,
+    };
+    let result = match &args.trace {
+        Some(path) => {
+            machine.enable_tracing(TraceOptions {
+                range: args.trace_lines.map(|lines| line_span(source, lines)),
+                limit: args.trace_limit,
+            });
+            let mut trace = BufWriter::new(File::create(path)?);
+            let result = machine.run_traced(&mut stdin(), &mut stdout(), &mut trace);
+            trace.flush()?;
+            if args.trace_limit.is_some() && machine.traced_events() == args.trace_limit {
+                eprintln!("note: the trace stopped at --trace-limit");
+            }
+            result
+        }
+        None => machine.run(&mut stdin(), &mut stdout()),
+    };
+    if let (
+        Some(path),
+        Err(BfError::Runtime {
+            kind: RuntimeErrorKind::OutOfFuel | RuntimeErrorKind::TimedOut,
+            ..
+        }),
+    ) = (&args.checkpoint, &result)
+    {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(file, &machine.snapshot()).map_err(io::Error::from)?;
+    }
+    result
+}
+
+/// Compares the traces of `args`, returning the exit status.
+fn trace_diff(args: &TraceDiffArgs) -> i32 {
+    let read = |path: &PathBuf| {
+        // Wide enough for the cells of every --cell-size
+        let events: io::Result<Vec<TraceEvent<i128>>> =
+            File::open(path).and_then(|file| trace::read(BufReader::new(file)));
+        events.unwrap_or_else(|e| {
+            eprintln!("error: unable to read {}: {e}", path.display());
+            exit(EXIT_IO)
+        })
+    };
+    let (a, b) = (read(&args.a), read(&args.b));
+    let source = args.source.as_ref().map(|name| {
+        let source = fs::read_to_string(name).unwrap_or_else(|e| {
+            eprintln!("error: unable to read {name}: {e}");
+            exit(EXIT_IO)
+        });
+        (name.as_str(), source)
+    });
+    let mut out = stdout().lock();
+    let (written, status) = match trace::diff(&a, &b) {
+        None => (
+            writeln!(out, "traces agree, over {} and {} events", a.len(), b.len()),
+            0,
+        ),
+        Some(divergence) => {
+            let (name_a, name_b) = (args.a.display().to_string(), args.b.display().to_string());
+            let report = divergence.report(
+                (&name_a, &a),
+                (&name_b, &b),
+                source
+                    .as_ref()
+                    .map(|(name, source)| (*name, source.as_str())),
+                args.context,
+            );
+            (write!(out, "{report}"), EXIT_DIVERGED)
+        }
+    };
+    match written.and_then(|()| out.flush()) {
+        Ok(()) => status,
+        Err(e) => {
+            eprintln!("error: {}", BfError::Io(e));
+            EXIT_IO
+        }
+    }
+}
+
+fn exit_code(error: &BfError) -> i32 {
+    match error {
+        BfError::Parse { .. } => EXIT_PARSE,
+        BfError::Runtime {
+            kind: RuntimeErrorKind::OutOfFuel,
+            ..
+        } => EXIT_OUT_OF_FUEL,
+        BfError::Runtime {
+            kind: RuntimeErrorKind::TimedOut | RuntimeErrorKind::Cancelled,
+            ..
+        } => EXIT_TIMEOUT,
+        BfError::Runtime { .. } => EXIT_RUNTIME,
+        BfError::Io(_) => EXIT_IO,
+        BfError::InvalidConfig(_) | BfError::InvalidSnapshot(_) | BfError::InvalidProgram(_) => {
+            EXIT_USAGE
+        }
+    }
 }
@@ -0,0 +1,487 @@
+use crate::{link_loops, Instr, Program, Span};
+use itertools::Itertools;
+use std::collections::BTreeMap;
+
+/// Names of the built-in passes, in the order they run at `OptLevel::O3`.
+pub const PASS_NAMES: [&str; 5] = [
+    "coalesce",
+    "clear-loops",
+    "mul-loops",
+    "scan-loops",
+    "offsets",
+];
+
+/// A rewrite of the instruction list.
+///
+/// Passes receive a linked program (every `LoopBegin`/`LoopEnd` holds the
+/// index of its partner) and may leave jump targets stale, as the
+/// `Optimizer` links the program again after each pass. They must keep
+/// brackets balanced, and give each instruction they produce a span
+/// covering the source of the instructions it replaces.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&self, program: Vec<(Instr, Span)>) -> Vec<(Instr, Span)>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    /// One instruction per source character
+    O0,
+    /// Run-length encoding of `+`/`-` and `>`/`<`
+    O1,
+    /// Clear, multiplication and scan loop recognition on top of O1
+    O2,
+    /// Every pass, including pointer offset folding
+    #[default]
+    O3,
+}
+
+impl TryFrom<u8> for OptLevel {
+    type Error = u8;
+
+    fn try_from(level: u8) -> Result<Self, u8> {
+        match level {
+            0 => Ok(Self::O0),
+            1 => Ok(Self::O1),
+            2 => Ok(Self::O2),
+            3 => Ok(Self::O3),
+            _ => Err(level),
+        }
+    }
+}
+
+/// An ordered list of passes.
+pub struct Optimizer {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Optimizer {
+    pub fn new(level: OptLevel) -> Self {
+        let mut passes: Vec<Box<dyn Pass>> = Vec::new();
+        if level >= OptLevel::O1 {
+            passes.push(Box::new(Coalesce));
+        }
+        if level >= OptLevel::O2 {
+            passes.push(Box::new(ClearLoops));
+            passes.push(Box::new(MulLoops));
+            passes.push(Box::new(ScanLoops));
+        }
+        if level >= OptLevel::O3 {
+            passes.push(Box::new(Offsets));
+        }
+        Self { passes }
+    }
+
+    /// Removes the pass called `name`, returning whether it was present.
+    pub fn disable(&mut self, name: &str) -> bool {
+        let len = self.passes.len();
+        self.passes.retain(|pass| pass.name() != name);
+        self.passes.len() != len
+    }
+
+    /// Appends a pass to run after the existing ones.
+    pub fn push(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    pub fn pass_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    pub fn run(&self, program: Program) -> Program {
+        let Program {
+            mut instrs,
+            mut spans,
+        } = program;
+        for pass in &self.passes {
+            (instrs, spans) = pass
+                .run(instrs.into_iter().zip(spans).collect())
+                .into_iter()
+                .unzip();
+            link_loops(&mut instrs)
+                .unwrap_or_else(|e| panic!("pass `{}` broke loops: {e:?}", pass.name()));
+        }
+        Program { instrs, spans }
+    }
+}
+
+/// Merges runs of `Add` and of `Ptr`.
+pub struct Coalesce;
+
+impl Pass for Coalesce {
+    fn name(&self) -> &'static str {
+        "coalesce"
+    }
+
+    fn run(&self, program: Vec<(Instr, Span)>) -> Vec<(Instr, Span)> {
+        program
+            .into_iter()
+            .coalesce(|a, b| match (&a, &b) {
+                ((Instr::Add(c, o), s), (Instr::Add(d, p), t)) if o == p => {
+                    Ok((Instr::Add(c.wrapping_add(*d), *o), join(s, t)))
+                }
+                ((Instr::Ptr(c), s), (Instr::Ptr(d), t)) => Ok((Instr::Ptr(c + d), join(s, t))),
+                _ => Err((a, b)),
+            })
+            .collect() // loosely inspired by https://stackoverflow.com/a/32717990
+    }
+}
+
+/// Replaces clear loops (`[-]` and `[+]`) with `Set(0)` and folds any
+/// directly following `Add` into the value being set.
+///
+/// A clear loop is recognised by its `LoopBegin` pointing exactly two
+/// instructions ahead.
+pub struct ClearLoops;
+
+impl Pass for ClearLoops {
+    fn name(&self) -> &'static str {
+        "clear-loops"
+    }
+
+    fn run(&self, program: Vec<(Instr, Span)>) -> Vec<(Instr, Span)> {
+        let mut optimized: Vec<(Instr, Span)> = Vec::with_capacity(program.len());
+        let mut i = 0;
+        while i < program.len() {
+            match (&program[i], program.get(i + 1)) {
+                ((Instr::LoopBegin(end), span), Some((Instr::Add(1 | -1, 0), _)))
+                    if *end == i + 2 =>
+                {
+                    let mut span = join(span, &program[i + 2].1);
+                    // A modification of the cell right before it is overwritten is dead
+                    if let Some((Instr::Add(_, 0) | Instr::Set(_, 0), _)) = optimized.last() {
+                        span = join(&optimized.pop().unwrap().1, &span);
+                    }
+                    optimized.push((Instr::Set(0, 0), span));
+                    i += 3;
+                    continue;
+                }
+                ((Instr::Add(x, 0), span), _) => match optimized.last_mut() {
+                    Some((Instr::Set(v, 0), set_span)) => {
+                        *v = v.wrapping_add(*x);
+                        *set_span = join(set_span, span);
+                    }
+                    _ => optimized.push(program[i].clone()),
+                },
+                (instr, _) => optimized.push(instr.clone()),
+            }
+            i += 1;
+        }
+        optimized
+    }
+}
+
+/// Replaces balanced loops that only add constants around a counter cell
+/// decremented (or incremented) by one, e.g. `[->+>++<<]`, with `MulAdd`.
+///
+/// Factors are normalised so that the loop always runs `cell` times: for a
+/// counter counting up to wrap-around they are negated.
+pub struct MulLoops;
+
+impl Pass for MulLoops {
+    fn name(&self) -> &'static str {
+        "mul-loops"
+    }
+
+    fn run(&self, program: Vec<(Instr, Span)>) -> Vec<(Instr, Span)> {
+        let mut optimized = Vec::with_capacity(program.len());
+        let mut i = 0;
+        while i < program.len() {
+            if let (Instr::LoopBegin(end), span) = &program[i] {
+                if let Some(factors) = mul_loop_factors(&program[i + 1..*end]) {
+                    optimized.push((Instr::MulAdd(factors), join(span, &program[*end].1)));
+                    i = end + 1;
+                    continue;
+                }
+            }
+            optimized.push(program[i].clone());
+            i += 1;
+        }
+        optimized
+    }
+}
+
+/// Returns the normalised `(offset, factor)` pairs of a multiplication loop
+/// body, or `None` if the body is not one.
+fn mul_loop_factors(body: &[(Instr, Span)]) -> Option<Vec<(isize, i64)>> {
+    let mut deltas: BTreeMap<isize, i64> = BTreeMap::new();
+    let mut offset = 0isize;
+    for (instr, _) in body {
+        match *instr {
+            Instr::Add(x, add_offset) => {
+                let delta = deltas.entry(offset.checked_add(add_offset)?).or_insert(0);
+                *delta = delta.wrapping_add(x);
+            }
+            Instr::Ptr(x) => offset = offset.checked_add(x)?,
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+    let negate = match deltas.remove(&0) {
+        Some(-1) => false,
+        Some(1) => true,
+        _ => return None,
+    };
+    Some(
+        deltas
+            .into_iter()
+            .filter(|&(_, factor)| factor != 0)
+            .map(|(offset, factor)| match negate {
+                true => (offset, factor.wrapping_neg()),
+                false => (offset, factor),
+            })
+            .collect(),
+    )
+}
+
+/// Replaces loops whose body is a single pointer move, e.g. `[<]` or `[>>]`,
+/// with `Scan`.
+pub struct ScanLoops;
+
+impl Pass for ScanLoops {
+    fn name(&self) -> &'static str {
+        "scan-loops"
+    }
+
+    fn run(&self, program: Vec<(Instr, Span)>) -> Vec<(Instr, Span)> {
+        let mut optimized = Vec::with_capacity(program.len());
+        let mut i = 0;
+        while i < program.len() {
+            match (&program[i], program.get(i + 1)) {
+                ((Instr::LoopBegin(end), span), Some(&(Instr::Ptr(x), _)))
+                    if *end == i + 2 && x != 0 =>
+                {
+                    optimized.push((Instr::Scan(x), join(span, &program[i + 2].1)));
+                    i += 3;
+                }
+                (instr, _) => {
+                    optimized.push(instr.clone());
+                    i += 1;
+                }
+            }
+        }
+        optimized
+    }
+}
+
+/// Folds pointer moves in straight-line code into the offsets of `Add`,
+/// `Set`, `Out` and `In`, e.g. `>+>++<<-` becomes `Add(1, 1)`, `Add(2, 2)`,
+/// `Add(-1, 0)`. The net move is emitted as a single `Ptr` before the next
+/// instruction that needs the real pointer (loops, `MulAdd`, `Scan`) and at
+/// the end of the program.
+pub struct Offsets;
+
+impl Pass for Offsets {
+    fn name(&self) -> &'static str {
+        "offsets"
+    }
+
+    fn run(&self, program: Vec<(Instr, Span)>) -> Vec<(Instr, Span)> {
+        let mut optimized = Vec::with_capacity(program.len());
+        let mut offset = 0isize;
+        // Source of the moves folded into `offset` so far
+        let mut moves: Option<Span> = None;
+        for (instr, span) in program {
+            let instr = match instr {
+                Instr::Ptr(x) => {
+                    offset += x;
+                    moves = Some(moves.map_or(span.clone(), |moves| join(&moves, &span)));
+                    continue;
+                }
+                Instr::Add(x, o) => Instr::Add(x, o + offset),
+                Instr::Set(x, o) => Instr::Set(x, o + offset),
+                Instr::Out(o) => Instr::Out(o + offset),
+                Instr::In(o) => Instr::In(o + offset),
+                instr => {
+                    if let Some(moves) = moves.take() {
+                        if offset != 0 {
+                            optimized.push((Instr::Ptr(offset), moves));
+                        }
+                        offset = 0;
+                    }
+                    instr
+                }
+            };
+            optimized.push((instr, span));
+        }
+        if let Some(moves) = moves {
+            if offset != 0 {
+                optimized.push((Instr::Ptr(offset), moves));
+            }
+        }
+        optimized
+    }
+}
+
+/// The smallest span covering both `a` and `b`.
+fn join(a: &Span, b: &Span) -> Span {
+    a.start.min(b.start)..a.end.max(b.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Instr::*;
+    use crate::ParseOptions;
+
+    fn optimize(source: &str) -> Vec<Instr> {
+        Program::parse_raw(source, ParseOptions::default())
+            .unwrap()
+            .optimize(OptLevel::O3)
+            .instrs
+    }
+    #[test]
+    fn clear_loops() {
+        assert_eq!(optimize(">[-]<[+]"), vec![Set(0, 1), Set(0, 0)]);
+    }
+    #[test]
+    fn set() {
+        assert_eq!(optimize("++[-]+++[-]--[+]."), vec![Set(0, 0), Out(0)]);
+        assert_eq!(
+            optimize("[[-]+++]"),
+            vec![LoopBegin(2), Set(3, 0), LoopEnd(0)]
+        );
+    }
+    #[test]
+    fn mul_loops() {
+        assert_eq!(
+            optimize("[->+>++<<]>[<<+++>>+>>>-<<<]"),
+            vec![
+                MulAdd(vec![(1, 1), (2, 2)]),
+                Ptr(1),
+                MulAdd(vec![(-2, -3), (3, 1)])
+            ]
+        );
+        assert_eq!(
+            optimize("[>+<->[-]<]"),
+            vec![LoopBegin(4), Add(1, 1), Add(-1, 0), Set(0, 1), LoopEnd(0)]
+        );
+    }
+    #[test]
+    fn scan_loops() {
+        assert_eq!(
+            optimize("[>]<<[<<>]+[<>]"),
+            vec![
+                Scan(1),
+                Ptr(-2),
+                Scan(-1),
+                Add(1, 0),
+                LoopBegin(5),
+                LoopEnd(4)
+            ]
+        );
+    }
+    #[test]
+    fn spans() {
+        let program = Program::parse_raw(" ++ [-]+\n>>[->+<]<.", ParseOptions::default())
+            .unwrap()
+            .optimize(OptLevel::O3);
+        assert_eq!(
+            program.instrs,
+            vec![Set(1, 0), Ptr(2), MulAdd(vec![(1, 1)]), Out(-1), Ptr(-1)]
+        );
+        assert_eq!(program.spans, vec![1..8, 9..11, 11..17, 18..19, 17..18]);
+    }
+    #[test]
+    fn offsets() {
+        assert_eq!(optimize(">+>++<<-"), vec![Add(1, 1), Add(2, 2), Add(-1, 0)]);
+        assert_eq!(
+            optimize(">,>>.<[<+>-<<]>.<<"),
+            vec![
+                In(1),
+                Out(3),
+                Ptr(2),
+                LoopBegin(7),
+                Add(1, -1),
+                Add(-1, 0),
+                Ptr(-2),
+                LoopEnd(3),
+                Out(1),
+                Ptr(-1)
+            ]
+        );
+    }
+    #[test]
+    fn levels() {
+        let source = "+++[->>+<<]>>[>]";
+        let at = |level| {
+            Program::parse_raw(source, ParseOptions::default())
+                .unwrap()
+                .optimize(level)
+                .instrs
+        };
+        assert_eq!(at(OptLevel::O0).len(), source.len());
+        assert_eq!(
+            at(OptLevel::O1),
+            vec![
+                Add(3, 0),
+                LoopBegin(6),
+                Add(-1, 0),
+                Ptr(2),
+                Add(1, 0),
+                Ptr(-2),
+                LoopEnd(1),
+                Ptr(2),
+                LoopBegin(10),
+                Ptr(1),
+                LoopEnd(8)
+            ]
+        );
+        assert_eq!(
+            at(OptLevel::O2),
+            vec![Add(3, 0), MulAdd(vec![(2, 1)]), Ptr(2), Scan(1)]
+        );
+    }
+    #[test]
+    fn disable() {
+        let mut optimizer = Optimizer::new(OptLevel::O3);
+        assert!(optimizer.disable("mul-loops"));
+        assert!(!optimizer.disable("mul-loops"));
+        assert_eq!(
+            optimizer
+                .run(Program::parse_raw("[->+<]", ParseOptions::default()).unwrap())
+                .instrs,
+            vec![LoopBegin(3), Add(-1, 0), Add(1, 1), LoopEnd(0)]
+        );
+    }
+
+    fn assert_equivalent(source: &str, input: &str) {
+        let program = Program::parse_raw(source, ParseOptions::default()).unwrap();
+        let mut expected = Vec::new();
+        program
+            .clone()
+            .run(&mut input.as_bytes(), &mut expected)
+            .unwrap();
+        for level in [OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let mut actual = Vec::new();
+            program
+                .clone()
+                .optimize(level)
+                .run(&mut input.as_bytes(), &mut actual)
+                .unwrap();
+            assert_eq!(actual, expected, "{level:?}");
+        }
+    }
+    #[test]
+    fn mul_equivalence() {
+        assert_equivalent(">>+++++[-<+++<++>>]<.<.", "");
+        assert_equivalent(",[+>+++<]>.", "a");
+        assert_equivalent(",>,<[->[->+>+<<]>[-<+>]<<]>>>.", "\x07\x0b");
+        assert_equivalent(include_str!("../bf_examples/alphabet_diagonal.bf"), "");
+        assert_equivalent(include_str!("../bf_examples/identity_matrix.bf"), "");
+    }
+    #[test]
+    fn scan_equivalence() {
+        assert_equivalent(">+>+>+>+<<<[>]+<[<]>.>.>.>.>.", "");
+        assert_equivalent(">>+>>+>>+>>+[<<]>>.>>.>>.", "");
+        assert_equivalent(">>+>+>+<<[>]<[-<]<.>.>.>.", "");
+        assert_equivalent("+>>>+>>>+>>>+>>>+<<<<<<<<<<<<[>>>]+.", "");
+    }
+    #[test]
+    fn offset_equivalence() {
+        assert_equivalent(">+>++<<->>>,<.>.>+[<<+>>-]<<.", "x");
+        assert_equivalent("+[>>>,.<<<-]>>>>>>.", "");
+    }
+}
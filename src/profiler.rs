@@ -0,0 +1,308 @@
+//! Where a program spends its time, from how often each instruction ran.
+
+use crate::{Instr, Program, SourcePos, Span};
+use std::fmt::Write;
+
+/// Execution counts of a program's instructions and loops.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    counts: Vec<u64>,
+    spans: Vec<Span>,
+    loops: Vec<LoopProfile>,
+}
+
+/// How much a loop of the program ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Index of the `LoopBegin`
+    pub begin: usize,
+    /// Index of the `LoopEnd`
+    pub end: usize,
+    /// Times the loop was reached
+    pub entries: u64,
+    /// Times its body ran
+    pub iterations: u64,
+    /// Instructions run from its `LoopBegin` to its `LoopEnd`, those of
+    /// nested loops included
+    pub instructions: u64,
+    pub span: Span,
+}
+
+impl Profile {
+    /// The profile of `program` given how often each of its instructions
+    /// ran, as counted by `Machine::instruction_counts`.
+    pub fn new(program: &Program, counts: &[u64]) -> Self {
+        let loops = program
+            .instrs()
+            .iter()
+            .enumerate()
+            .filter_map(|(begin, instr)| match *instr {
+                Instr::LoopBegin(end) => Some(LoopProfile {
+                    begin,
+                    end,
+                    // `LoopEnd` jumps past the `LoopBegin`, which thus only
+                    // runs on entry
+                    entries: counts[begin],
+                    iterations: counts[end],
+                    instructions: counts[begin..=end].iter().sum(),
+                    span: program.spans()[begin].start..program.spans()[end].end,
+                }),
+                _ => None,
+            })
+            .collect();
+        Self {
+            counts: counts.to_vec(),
+            spans: program.spans().to_vec(),
+            loops,
+        }
+    }
+
+    /// Executions of each instruction.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Every loop, in the order of the program.
+    pub fn loops(&self) -> &[LoopProfile] {
+        &self.loops
+    }
+
+    /// Number of instructions run.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// A table of the `limit` loops and the `limit` instructions that ran
+    /// the most, located in `source`.
+    pub fn report(&self, source: &str, limit: usize) -> String {
+        let mut out = format!("{} instructions run\n", self.total());
+        let mut loops: Vec<_> = self.loops.iter().filter(|l| l.entries > 0).collect();
+        loops.sort_by_key(|l| (std::cmp::Reverse(l.instructions), l.begin));
+        if !loops.is_empty() {
+            out.push_str("\nHottest loops:\n");
+            writeln!(
+                out,
+                "{:>14} {:>12} {:>10}  {:<10} source",
+                "instructions", "iterations", "entries", "at"
+            )
+            .unwrap();
+            for l in loops.into_iter().take(limit) {
+                writeln!(
+                    out,
+                    "{:>14} {:>12} {:>10}  {:<10} {}",
+                    l.instructions,
+                    l.iterations,
+                    l.entries,
+                    location(source, &l.span),
+                    excerpt(source, &l.span)
+                )
+                .unwrap();
+            }
+        }
+        let mut instrs: Vec<_> = (0..self.counts.len())
+            .filter(|&pc| self.counts[pc] > 0)
+            .collect();
+        instrs.sort_by_key(|&pc| (std::cmp::Reverse(self.counts[pc]), pc));
+        if !instrs.is_empty() {
+            out.push_str("\nHottest instructions:\n");
+            writeln!(out, "{:>14} {:>12}  {:<10} source", "count", "index", "at").unwrap();
+            for pc in instrs.into_iter().take(limit) {
+                let span = &self.spans[pc];
+                writeln!(
+                    out,
+                    "{:>14} {:>12}  {:<10} {}",
+                    self.counts[pc],
+                    pc,
+                    location(source, span),
+                    excerpt(source, span)
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+
+    /// Executions of the instructions starting on each line of `source`, or
+    /// `None` for the lines where no instruction starts.
+    pub fn line_counts(&self, source: &str) -> Vec<Option<u64>> {
+        let mut lines = vec![None; source.lines().count().max(1)];
+        let newlines: Vec<usize> = source.match_indices('\n').map(|(i, _)| i).collect();
+        for (count, span) in self.counts.iter().zip(&self.spans) {
+            let line = newlines
+                .partition_point(|&i| i < span.start)
+                .min(lines.len() - 1);
+            *lines[line].get_or_insert(0) += count;
+        }
+        lines
+    }
+
+    /// `source` with the executions of each line in a gutter.
+    pub fn annotate(&self, source: &str) -> String {
+        let counts = self.line_counts(source);
+        let width = counts
+            .iter()
+            .flatten()
+            .max()
+            .map_or(1, |c| c.to_string().len());
+        let mut out = String::new();
+        for (line, count) in source.lines().zip(counts) {
+            let count = count.map_or(String::new(), |c| c.to_string());
+            let annotated = format!("{count:>width$} | {line}");
+            writeln!(out, "{}", annotated.trim_end()).unwrap();
+        }
+        out
+    }
+
+    /// A standalone HTML page of `source`, the contents of the file `name`,
+    /// with each line shaded by how often it ran.
+    pub fn html(&self, name: &str, source: &str) -> String {
+        let counts = self.line_counts(source);
+        // Logarithmic, or the hottest loop would wash out everything else
+        let max = counts.iter().flatten().max().map_or(0, |&c| c);
+        let heat = |count: u64| (count as f64).ln_1p() / (max as f64).ln_1p().max(1.0);
+        let mut out = format!(
+            "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Profile of {name}</title>
+<style>
+body {{ font-family: monospace; }}
+table {{ border-collapse: collapse; }}
+td {{ padding: 0 0.5em; white-space: pre; }}
+td.count {{ text-align: right; color: #666; border-right: 1px solid #ccc; }}
+</style>
+</head>
+<body>
+<h1>Profile of {name}</h1>
+<p>{total} instructions run</p>
+<table>
+",
+            name = escape(name),
+            total = self.total(),
+        );
+        for (line, count) in source.lines().zip(counts) {
+            let (count, alpha) = match count {
+                Some(c) => (c.to_string(), heat(c)),
+                None => (String::new(), 0.0),
+            };
+            writeln!(
+                out,
+                "<tr style=\"background: rgba(255, 64, 0, {alpha:.3})\">\
+                 <td class=\"count\">{count}</td><td>{}</td></tr>",
+                escape(line)
+            )
+            .unwrap();
+        }
+        out.push_str("</table>\n</body>\n</html>\n");
+        out
+    }
+}
+
+/// `text` with the characters special to HTML escaped.
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `line:column` of the start of `span`.
+fn location(source: &str, span: &Span) -> String {
+    let pos = SourcePos::new(source, span.start.min(source.len()));
+    format!("{}:{}", pos.line, pos.column)
+}
+
+/// The commands in `span`, shortened to fit a table.
+fn excerpt(source: &str, span: &Span) -> String {
+    const MAX_LEN: usize = 30;
+    let text = source.get(span.clone()).unwrap_or("");
+    let commands: String = text.chars().filter(|c| "+-<>[].,#".contains(*c)).collect();
+    match commands.char_indices().nth(MAX_LEN) {
+        Some((end, _)) => format!("{}...", &commands[..end]),
+        None => commands,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Machine, ParseOptions};
+
+    fn profile(source: &str) -> Profile {
+        let program = Program::parse_raw(source, ParseOptions::default()).unwrap();
+        let mut machine = Machine::<u8>::new(program, Config::default()).unwrap();
+        machine.enable_profiling();
+        machine.run_until_input().unwrap();
+        Profile::new(machine.program(), machine.instruction_counts().unwrap())
+    }
+    #[test]
+    fn loops() {
+        let profile = profile("+++[>++[>+<-]<-]\n[-]");
+        let loops = profile.loops();
+        assert_eq!(loops.len(), 3);
+        assert_eq!(
+            loops[0],
+            LoopProfile {
+                begin: 3,
+                end: 15,
+                entries: 1,
+                iterations: 3,
+                instructions: 1 + 3 * (3 + 1 + 2 * 5 + 3),
+                span: 3..16,
+            }
+        );
+        assert_eq!((loops[1].entries, loops[1].iterations), (3, 6));
+        assert_eq!(loops[1].instructions, 3 + 6 * 5);
+        assert_eq!((loops[2].entries, loops[2].iterations), (1, 0));
+        assert_eq!(profile.total(), 3 + loops[0].instructions + 1);
+    }
+    #[test]
+    fn report() {
+        let profile = profile("++[\n  >+<-\n]");
+        assert_eq!(
+            profile.report("++[\n  >+<-\n]", 2),
+            "\
+13 instructions run
+
+Hottest loops:
+  instructions   iterations    entries  at         source
+            11            2          1  1:3        [>+<-]
+
+Hottest instructions:
+         count        index  at         source
+             2            3  2:3        >
+             2            4  2:4        +
+"
+        );
+    }
+    #[test]
+    fn annotate() {
+        let source = "loop\n++[\n  >+<-\n]\n";
+        let profile = profile(source);
+        assert_eq!(
+            profile.line_counts(source),
+            [None, Some(3), Some(8), Some(2)]
+        );
+        assert_eq!(
+            profile.annotate(source),
+            "  | loop\n3 | ++[\n8 |   >+<-\n2 | ]\n"
+        );
+    }
+    #[test]
+    fn html() {
+        let source = "\"a&b\"\n+[-]";
+        let html = profile(source).html("<x>.bf", source);
+        assert!(html.contains("<title>Profile of &lt;x&gt;.bf</title>"));
+        assert!(html.contains(">&quot;a&amp;b&quot;</td>"));
+        assert!(html.contains("rgba(255, 64, 0, 0.000)\"><td class=\"count\"></td>"));
+        assert!(html.contains("rgba(255, 64, 0, 1.000)\"><td class=\"count\">4</td><td>+[-]</td>"));
+    }
+}
@@ -0,0 +1,309 @@
+use crate::Cell;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Number of cells a growable tape starts with, and the classic tape size.
+pub const DEFAULT_SIZE: usize = 30000;
+
+/// What happens when the pointer leaves the cells that exist so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TapePolicy {
+    /// Starts at cell 0 and grows to the right on demand; moving left of
+    /// cell 0 is an error
+    #[default]
+    Growable,
+    /// Grows on demand in both directions
+    Infinite,
+    /// A ring of the given number of cells
+    Circular(usize),
+    /// The given number of cells; leaving them is an error
+    Fixed(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeError {
+    /// The pointer went left of cell 0 of a `Growable` tape
+    Underflow,
+    /// The pointer left a `Fixed` tape
+    OutOfBounds,
+    /// The pointer does not fit an `isize`
+    Overflow,
+    /// Growing the tape would take more memory than allowed
+    MemoryLimit,
+}
+
+impl std::error::Error for TapeError {}
+
+impl fmt::Display for TapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TapeError::Underflow => write!(f, "BF pointer underflow"),
+            TapeError::OutOfBounds => write!(f, "BF pointer out of bounds"),
+            TapeError::Overflow => write!(f, "BF pointer overflow"),
+            TapeError::MemoryLimit => write!(f, "BF tape exceeds the memory limit"),
+        }
+    }
+}
+
+/// The cells together with the pointer into them.
+///
+/// Positions are logical: cell 0 is where the pointer starts, and an
+/// `Infinite` tape also has negative positions. `origin` is the index of
+/// cell 0 in `cells`.
+///
+/// Growable tapes at least double in size whenever they grow, but never
+/// beyond `max_len` cells.
+#[derive(Debug, Clone)]
+pub(crate) struct Tape<C> {
+    cells: Vec<C>,
+    origin: usize,
+    ptr: usize,
+    policy: TapePolicy,
+    max_len: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    /// `memory_limit` is in bytes. A `Circular` or `Fixed` tape must have
+    /// at least one cell.
+    pub fn new(policy: TapePolicy, memory_limit: Option<usize>) -> Result<Self, TapeError> {
+        let max_len = memory_limit.map_or(usize::MAX, |bytes| bytes / size_of::<C>());
+        let len = match policy {
+            TapePolicy::Growable | TapePolicy::Infinite => DEFAULT_SIZE.min(max_len).max(1),
+            TapePolicy::Circular(size) | TapePolicy::Fixed(size) => {
+                assert!(size > 0, "tape size must not be 0");
+                size
+            }
+        };
+        if len > max_len {
+            return Err(TapeError::MemoryLimit);
+        }
+        Ok(Self {
+            cells: vec![C::default(); len],
+            origin: 0,
+            ptr: 0,
+            policy,
+            max_len,
+        })
+    }
+
+    /// A tape holding `cells`, of which `origin` is cell 0, with the pointer
+    /// at `position`. Fails if that is not a tape `new` could have grown
+    /// into.
+    pub fn restore(
+        policy: TapePolicy,
+        memory_limit: Option<usize>,
+        cells: Vec<C>,
+        origin: usize,
+        position: isize,
+    ) -> Result<Self, &'static str> {
+        let mut tape =
+            Self::new(policy, memory_limit).map_err(|_| "tape exceeds the memory limit")?;
+        let ptr = (origin as isize)
+            .checked_add(position)
+            .and_then(|ptr| usize::try_from(ptr).ok())
+            .filter(|&ptr| ptr < cells.len())
+            .ok_or("pointer is off the tape")?;
+        match policy {
+            TapePolicy::Circular(size) | TapePolicy::Fixed(size) if cells.len() != size => {
+                return Err("tape size does not match")
+            }
+            TapePolicy::Growable if origin != 0 => return Err("tape grew to the left"),
+            _ if cells.len() > tape.max_len => return Err("tape exceeds the memory limit"),
+            _ => (),
+        }
+        tape.cells = cells;
+        tape.origin = origin;
+        tape.ptr = ptr;
+        Ok(tape)
+    }
+
+    /// All cells, and the index of cell 0 among them.
+    pub fn cells(&self) -> (&[C], usize) {
+        (&self.cells, self.origin)
+    }
+
+    /// Logical position of the pointer.
+    pub fn position(&self) -> isize {
+        self.ptr as isize - self.origin as isize
+    }
+
+    /// The cell at logical `position`, if the tape has it yet.
+    pub fn get(&self, position: isize) -> Option<C> {
+        let index = (self.origin as isize).checked_add(position)?;
+        self.cells.get(usize::try_from(index).ok()?).copied()
+    }
+
+    pub fn current(&self) -> C {
+        self.cells[self.ptr]
+    }
+
+    pub fn set_current(&mut self, value: C) {
+        self.cells[self.ptr] = value;
+    }
+
+    /// The cell `offset` away from the pointer.
+    pub fn cell(&mut self, offset: isize) -> Result<&mut C, TapeError> {
+        let index = self.index(offset)?;
+        Ok(&mut self.cells[index])
+    }
+
+    /// The cell `offset` away from the pointer, without growing the tape.
+    pub fn peek(&self, offset: isize) -> C {
+        let Some(index) = (self.ptr as isize).checked_add(offset) else {
+            return C::default();
+        };
+        let index = match self.policy {
+            TapePolicy::Circular(_) => index.rem_euclid(self.cells.len() as isize),
+            _ => index,
+        };
+        usize::try_from(index)
+            .ok()
+            .and_then(|index| self.cells.get(index).copied())
+            .unwrap_or_default()
+    }
+
+    /// Moves the pointer by `offset`.
+    pub fn shift(&mut self, offset: isize) -> Result<(), TapeError> {
+        self.ptr = self.index(offset)?;
+        Ok(())
+    }
+
+    /// Moves the pointer by `stride` until it is on a zero cell, or at most
+    /// once round a circular tape, which may have none. Returns the number
+    /// of moves.
+    pub fn scan(&mut self, stride: isize) -> Result<usize, TapeError> {
+        match (self.policy, stride) {
+            (TapePolicy::Circular(_), _) => self.scan_stepwise(stride, self.cells.len()),
+            (_, 1) => match C::find_zero(&self.cells[self.ptr..]) {
+                Some(offset) => {
+                    self.ptr += offset;
+                    Ok(offset)
+                }
+                // Everything past the end of the tape is zero
+                None => {
+                    let moves = self.cells.len() - self.ptr;
+                    self.ptr = self.cells.len() - 1;
+                    self.shift(1)?;
+                    Ok(moves)
+                }
+            },
+            (_, -1) => match C::rfind_zero(&self.cells[..=self.ptr]) {
+                Some(index) => {
+                    let moves = self.ptr - index;
+                    self.ptr = index;
+                    Ok(moves)
+                }
+                None => {
+                    let moves = self.ptr + 1;
+                    self.ptr = 0;
+                    self.shift(-1)?;
+                    Ok(moves)
+                }
+            },
+            _ => self.scan_stepwise(stride, usize::MAX),
+        }
+    }
+
+    fn scan_stepwise(&mut self, stride: isize, limit: usize) -> Result<usize, TapeError> {
+        let mut moves = 0;
+        while !self.current().is_zero() && moves < limit {
+            self.shift(stride)?;
+            moves += 1;
+        }
+        Ok(moves)
+    }
+
+    /// Index in `cells` of the cell `offset` away from the pointer, growing
+    /// or wrapping the tape as its policy says.
+    fn index(&mut self, offset: isize) -> Result<usize, TapeError> {
+        let index = (self.ptr as isize)
+            .checked_add(offset)
+            .ok_or(TapeError::Overflow)?;
+        let len = self.cells.len() as isize;
+        match self.policy {
+            TapePolicy::Circular(_) => Ok(index.rem_euclid(len) as usize),
+            _ if (0..len).contains(&index) => Ok(index as usize),
+            TapePolicy::Fixed(_) => Err(TapeError::OutOfBounds),
+            TapePolicy::Growable | TapePolicy::Infinite if index >= len => {
+                let extra = self.growth(index as usize + 1 - self.cells.len())?;
+                self.cells.resize(self.cells.len() + extra, C::default());
+                Ok(index as usize)
+            }
+            TapePolicy::Growable => Err(TapeError::Underflow),
+            TapePolicy::Infinite => {
+                let missing = index.unsigned_abs();
+                let extra = self.growth(missing)?;
+                self.cells
+                    .splice(0..0, std::iter::repeat_n(C::default(), extra));
+                self.origin += extra;
+                self.ptr += extra;
+                Ok(extra - missing)
+            }
+        }
+    }
+
+    /// Number of cells to add to have at least `missing` more.
+    fn growth(&self, missing: usize) -> Result<usize, TapeError> {
+        let room = self.max_len.saturating_sub(self.cells.len());
+        if missing > room {
+            return Err(TapeError::MemoryLimit);
+        }
+        Ok(missing.max(self.cells.len()).min(room))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grow_left() {
+        let mut tape = Tape::<u8>::new(TapePolicy::Infinite, None).unwrap();
+        *tape.cell(1).unwrap() = 1;
+        tape.shift(-3).unwrap();
+        assert_eq!(tape.position(), -3);
+        tape.set_current(2);
+        tape.shift(4).unwrap();
+        assert_eq!(tape.position(), 1);
+        assert_eq!(tape.current(), 1);
+        assert_eq!(*tape.cell(-4).unwrap(), 2);
+    }
+    #[test]
+    fn grow_right() {
+        let mut tape = Tape::<u16>::new(TapePolicy::Growable, None).unwrap();
+        tape.shift(DEFAULT_SIZE as isize).unwrap();
+        assert_eq!(tape.cells.len(), DEFAULT_SIZE * 2);
+        tape.shift(DEFAULT_SIZE as isize * 2).unwrap();
+        assert_eq!(tape.cells.len(), DEFAULT_SIZE * 4);
+        tape.set_current(7);
+        let back = -(DEFAULT_SIZE as isize) * 3;
+        assert_eq!(tape.shift(back - 1), Err(TapeError::Underflow));
+        tape.shift(back).unwrap();
+        assert_eq!(*tape.cell(-back).unwrap(), 7);
+    }
+    #[test]
+    fn circular_scan() {
+        let mut tape = Tape::<u8>::new(TapePolicy::Circular(4), None).unwrap();
+        for _ in 0..4 {
+            tape.set_current(1);
+            tape.shift(1).unwrap();
+        }
+        assert_eq!(tape.scan(3), Ok(4));
+        assert_eq!(tape.position(), 0);
+        *tape.cell(2).unwrap() = 0;
+        assert_eq!(tape.scan(-2), Ok(1));
+        assert_eq!(tape.position(), 2);
+    }
+    #[test]
+    fn memory_limit() {
+        let mut tape = Tape::<u8>::new(TapePolicy::Infinite, Some(100_000)).unwrap();
+        tape.shift(-10).unwrap();
+        assert_eq!(tape.cells.len(), DEFAULT_SIZE * 2);
+        tape.shift(30010).unwrap();
+        assert_eq!(tape.cells.len(), 100_000);
+        assert_eq!(tape.shift(40000), Err(TapeError::MemoryLimit));
+        assert_eq!(tape.position(), 30000);
+        assert!(Tape::<u8>::new(TapePolicy::Fixed(100_001), Some(100_000)).is_err());
+        assert!(Tape::<u32>::new(TapePolicy::Growable, Some(400)).is_ok());
+    }
+}
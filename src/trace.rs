@@ -0,0 +1,370 @@
+//! Step-by-step records of a run, written as JSON lines, and where two of
+//! them part ways.
+
+use crate::diagnostics::{snippet, Mark};
+use crate::{Instr, Span};
+use itertools::{EitherOrBoth, Itertools};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::{Debug, Write as _};
+use std::io::{self, BufRead, ErrorKind, Write};
+
+/// What `Machine::enable_tracing` records.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// Only record the instructions whose source overlaps these bytes, all
+    /// of them if `None`
+    pub range: Option<Span>,
+    /// Most events to record, unbounded if `None`
+    pub limit: Option<u64>,
+}
+
+impl TraceOptions {
+    /// Whether to record an instruction from the source bytes `span`.
+    pub(crate) fn covers(&self, span: &Span) -> bool {
+        self.range
+            .as_ref()
+            .is_none_or(|range| span.start < range.end && range.start < span.end)
+    }
+}
+
+/// One instruction that ran, and the state it left the machine in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEvent<C = u8> {
+    /// Steps taken before the instruction, counted like `Config::max_steps`
+    pub step: u64,
+    pub pc: usize,
+    pub instr: Instr,
+    pub span: Span,
+    /// Position of the pointer after the instruction
+    pub pointer: isize,
+    /// The cell under the pointer after the instruction
+    pub cell: C,
+    /// Bytes the instruction wrote
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output: Vec<u8>,
+}
+
+/// Writes `events` to `writer`, one JSON object per line.
+pub fn write<C: Serialize>(writer: &mut impl Write, events: &[TraceEvent<C>]) -> io::Result<()> {
+    for event in events {
+        serde_json::to_writer(&mut *writer, event)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Reads events written by `write`.
+pub fn read<C: DeserializeOwned>(reader: impl BufRead) -> io::Result<Vec<TraceEvent<C>>> {
+    let mut events = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("line {}: {e}", i + 1)))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/// How two traces disagree, see `diff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// They run different instructions
+    Path,
+    /// The same instruction leaves a different pointer or cell
+    State,
+    /// They write different bytes
+    Output,
+    /// One ends while the other goes on
+    Length,
+}
+
+/// The first place two traces disagree: an event of each, or the length
+/// of a trace that ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub kind: DivergenceKind,
+    pub a: usize,
+    pub b: usize,
+}
+
+/// Finds where traces `a` and `b` of the same source first disagree.
+///
+/// The traces may come from different optimization levels, so only the
+/// events of instructions found in both, with the same source span, are
+/// compared step by step. Output is compared byte by byte, whatever wrote
+/// it.
+pub fn diff<C: PartialEq>(a: &[TraceEvent<C>], b: &[TraceEvent<C>]) -> Option<Divergence> {
+    let key = |e: &TraceEvent<C>| {
+        // Jump targets are indices, which differ between optimization levels
+        let instr = match e.instr {
+            Instr::LoopBegin(_) => Instr::LoopBegin(0),
+            Instr::LoopEnd(_) => Instr::LoopEnd(0),
+            ref instr => instr.clone(),
+        };
+        (instr, e.span.clone())
+    };
+    let (keys_a, keys_b): (HashSet<_>, HashSet<_>) =
+        (a.iter().map(key).collect(), b.iter().map(key).collect());
+    let common = |trace: &[TraceEvent<C>], keys: &HashSet<(Instr, Span)>| {
+        (0..trace.len())
+            .filter(|&i| keys.contains(&key(&trace[i])))
+            .collect::<Vec<_>>()
+    };
+    let steps = common(a, &keys_b)
+        .into_iter()
+        .zip_longest(common(b, &keys_a))
+        .find_map(|pair| match pair {
+            EitherOrBoth::Both(i, j) if key(&a[i]) != key(&b[j]) => {
+                Some((DivergenceKind::Path, i, j))
+            }
+            EitherOrBoth::Both(i, j)
+                if (a[i].pointer, &a[i].cell) != (b[j].pointer, &b[j].cell) =>
+            {
+                Some((DivergenceKind::State, i, j))
+            }
+            EitherOrBoth::Both(..) => None,
+            EitherOrBoth::Left(i) => Some((DivergenceKind::Length, i, b.len())),
+            EitherOrBoth::Right(j) => Some((DivergenceKind::Length, a.len(), j)),
+        });
+    let bytes = |trace: &[TraceEvent<C>]| {
+        trace
+            .iter()
+            .enumerate()
+            .flat_map(|(i, e)| e.output.iter().map(move |&byte| (i, byte)))
+            .collect::<Vec<_>>()
+    };
+    let output = bytes(a)
+        .into_iter()
+        .zip_longest(bytes(b))
+        .find_map(|pair| match pair {
+            EitherOrBoth::Both((i, x), (j, y)) => (x != y).then_some((i, j)),
+            EitherOrBoth::Left((i, _)) => Some((i, b.len())),
+            EitherOrBoth::Right((j, _)) => Some((a.len(), j)),
+        })
+        .map(|(i, j)| (DivergenceKind::Output, i, j));
+    // The earlier of the two, as the first trace ran
+    [steps, output]
+        .into_iter()
+        .flatten()
+        .min_by_key(|&(_, i, j)| (i, j))
+        .map(|(kind, a, b)| Divergence { kind, a, b })
+}
+
+impl Divergence {
+    /// Describes the divergence between traces `a` and `b`, each given with
+    /// its name, with up to `context` events before it, and where it is in
+    /// `source` if given with its name.
+    pub fn report<C: Debug>(
+        &self,
+        (name_a, a): (&str, &[TraceEvent<C>]),
+        (name_b, b): (&str, &[TraceEvent<C>]),
+        source: Option<(&str, &str)>,
+        context: usize,
+    ) -> String {
+        let what = match self.kind {
+            DivergenceKind::Path => "different instructions run",
+            DivergenceKind::State => "the pointer or the cell under it differs",
+            DivergenceKind::Output => "different output",
+            DivergenceKind::Length => "one trace ends first",
+        };
+        let mut out = format!("traces diverge: {what}\n");
+        for (name, trace, at) in [(name_a, a, self.a), (name_b, b, self.b)] {
+            match trace.get(at) {
+                Some(e) => writeln!(out, "\n{name}, event {at} at step {}:", e.step),
+                None => writeln!(out, "\n{name}, ended after {at} events:"),
+            }
+            .unwrap();
+            writeln!(
+                out,
+                "  {:>8} {:>10} {:>6} {:>8} {:>6}  instruction, output",
+                "event", "step", "pc", "pointer", "cell"
+            )
+            .unwrap();
+            for (i, e) in trace
+                .iter()
+                .enumerate()
+                .take(at + 1)
+                .skip(at.saturating_sub(context))
+            {
+                writeln!(
+                    out,
+                    "{} {:>8} {:>10} {:>6} {:>8} {:>6}  {:?}{}",
+                    if i == at { '>' } else { ' ' },
+                    i,
+                    e.step,
+                    e.pc,
+                    e.pointer,
+                    format!("{:?}", e.cell),
+                    e.instr,
+                    match e.output.is_empty() {
+                        true => String::new(),
+                        false => format!(", \"{}\"", e.output.escape_ascii()),
+                    }
+                )
+                .unwrap();
+            }
+        }
+        if let Some((name, source)) = source {
+            let spans: Vec<_> = [(name_a, a.get(self.a)), (name_b, b.get(self.b))]
+                .into_iter()
+                .filter_map(|(trace, e)| Some((trace, e?.span.clone())))
+                .collect();
+            // The traces are read from files, and may be of another source
+            let fits = |span: &Span| {
+                span.start <= span.end
+                    && span.end <= source.len()
+                    && source.is_char_boundary(span.start)
+                    && source.is_char_boundary(span.end)
+            };
+            if !spans.iter().all(|(_, span)| fits(span)) {
+                writeln!(
+                    out,
+                    "\nnote: the traces are not of {name}, so it is not shown"
+                )
+                .unwrap();
+            } else if !spans.is_empty() {
+                let marks = spans
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (trace, span))| match i {
+                        0 => Mark::primary(span, trace),
+                        _ => Mark::secondary(span, trace),
+                    })
+                    .collect();
+                out.push('\n');
+                snippet(&mut out, name, source, marks);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Machine, OptLevel, ParseOptions, Program};
+
+    fn trace(source: &str, level: OptLevel) -> Vec<TraceEvent> {
+        let program = Program::parse_raw(source, ParseOptions::default())
+            .unwrap()
+            .optimize(level);
+        let mut machine = Machine::new(program, Config::default()).unwrap();
+        machine.enable_tracing(TraceOptions::default());
+        machine.run_until_input().unwrap();
+        machine.take_trace()
+    }
+
+    #[test]
+    fn covers() {
+        let options = TraceOptions {
+            range: Some(4..8),
+            limit: None,
+        };
+        assert!(options.covers(&(0..5)));
+        assert!(options.covers(&(7..12)));
+        assert!(!options.covers(&(0..4)));
+        assert!(!options.covers(&(8..9)));
+        assert!(TraceOptions::default().covers(&(8..9)));
+    }
+    #[test]
+    fn diffs() {
+        let source = "++[->+++<]>.[-]+>>[-]<<.";
+        let o0 = trace(source, OptLevel::O0);
+        let o3 = trace(source, OptLevel::O3);
+        assert_eq!(diff(&o0, &o3), None);
+        assert_eq!(diff(&o0, &o0[..10]).unwrap().kind, DivergenceKind::Length);
+
+        let mut wrong = o3.clone();
+        let i = wrong.iter().position(|e| !e.output.is_empty()).unwrap();
+        wrong[i].output = vec![7];
+        let out = o0.iter().position(|e| !e.output.is_empty()).unwrap();
+        let divergence = diff(&o0, &wrong).unwrap();
+        assert_eq!(
+            divergence,
+            Divergence {
+                kind: DivergenceKind::Output,
+                a: out,
+                b: i
+            }
+        );
+
+        let looped = "++[>+.<-]";
+        let mut wrong = trace(looped, OptLevel::O3);
+        let i = wrong
+            .iter()
+            .rposition(|e| matches!(e.instr, Instr::LoopEnd(_)))
+            .unwrap();
+        wrong[i].cell = 5;
+        let divergence = diff(&trace(looped, OptLevel::O0), &wrong).unwrap();
+        assert_eq!(divergence.kind, DivergenceKind::State);
+        assert_eq!(divergence.b, i);
+
+        let mut wrong = o0.clone();
+        wrong[4].cell = 9;
+        let divergence = diff(&o0, &wrong).unwrap();
+        assert_eq!(
+            divergence,
+            Divergence {
+                kind: DivergenceKind::State,
+                a: 4,
+                b: 4
+            }
+        );
+        let report = divergence.report(("a", &o0), ("b", &wrong), Some(("x.bf", source)), 1);
+        assert_eq!(
+            report,
+            r#"traces diverge: the pointer or the cell under it differs
+
+a, event 4 at step 4:
+     event       step     pc  pointer   cell  instruction, output
+         3          3      3        0      1  Add(-1, 0)
+>        4          4      4        1      0  Ptr(1)
+
+b, event 4 at step 4:
+     event       step     pc  pointer   cell  instruction, output
+         3          3      3        0      1  Add(-1, 0)
+>        4          4      4        1      9  Ptr(1)
+
+ --> x.bf:1:5
+  |
+1 | ++[->+++<]>.[-]+>>[-]<<.
+  |     ^ a
+  |     - b
+"#
+        );
+        let report = divergence.report(("a", &o0), ("b", &wrong), Some(("x.bf", "ééé+.")), 1);
+        assert!(report.ends_with("\nnote: the traces are not of x.bf, so it is not shown\n"));
+    }
+    #[test]
+    fn json_lines() {
+        let event = TraceEvent {
+            step: 3,
+            pc: 2,
+            instr: Instr::Out(0),
+            span: 2..3,
+            pointer: 1,
+            cell: 65u8,
+            output: vec![65],
+        };
+        let silent = TraceEvent {
+            output: vec![],
+            ..event.clone()
+        };
+        let mut out = Vec::new();
+        write(&mut out, &[event.clone(), silent.clone()]).unwrap();
+        let events: Vec<TraceEvent> = read(&out[..]).unwrap();
+        assert_eq!(events, [event, silent]);
+        assert!(read::<u8>(&b"{}"[..]).is_err());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"step":3,"pc":2,"instr":{"Out":0},"span":{"start":2,"end":3},"pointer":1,"cell":65,"output":[65]}
+{"step":3,"pc":2,"instr":{"Out":0},"span":{"start":2,"end":3},"pointer":1,"cell":65}
+"#
+        );
+    }
+}